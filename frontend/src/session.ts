@@ -31,6 +31,11 @@ interface JWTResponse {
 
 type SessionResponse = QRResponse | StatusResponse | JWTResponse;
 
+export interface Requirement {
+  attribute: string,
+  value?: string,
+}
+
 interface SessionState {
   status: null | SessionStatus,
   qrCode: null | string,
@@ -117,6 +122,30 @@ class Session {
     this.socket.send('start');
     this.socket.addEventListener('message', this.handleMessage.bind(this));
   }
+
+  /**
+   * Log in under a pseudonym, the disclosed attributes are never shown to other users
+   */
+  public async startPseudonymous(pseudonym: string | null) {
+    this.status = SessionStatus.INITIALIZED;
+    this.store.set(this.getState());
+
+    await this.connect();
+    this.socket.send(JSON.stringify({ action: 'pseudonymous', pseudonym }));
+    this.socket.addEventListener('message', this.handleMessage.bind(this));
+  }
+
+  /**
+   * Disclose the attributes required by a gated room, resulting in a new JWT for the session
+   */
+  public async disclose(token: string, attributes: Requirement[]) {
+    this.status = SessionStatus.INITIALIZED;
+    this.store.set(this.getState());
+
+    await this.connect();
+    this.socket.send(JSON.stringify({ action: 'disclose', token, attributes }));
+    this.socket.addEventListener('message', this.handleMessage.bind(this));
+  }
 }
 
 const session = new Session();
@@ -0,0 +1,216 @@
+use crate::content;
+use crate::errors::Error;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+// number of bytes of an attachment sent in a single binary frame to a downloading client
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+// maximum number of characters of the file name of an attachment
+const MAX_NAME_LENGTH: usize = 255;
+
+// a file attached to a chat message, its content is stored under its hash,
+// i.e. {"name":"cat.png","mime":"image/png","size":5120,"hash":"9f86d081..."}
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub name: String,
+    pub mime: String,
+    pub size: usize,
+    // hex encoded SHA-256 of the content
+    pub hash: String,
+}
+
+// which attachments clients can upload
+#[derive(Debug, Clone)]
+pub struct AttachmentLimits {
+    // maximum size of a single attachment in bytes
+    pub max_size: usize,
+    // allowed MIME types, i.e. "image/png"
+    pub types: Vec<String>,
+}
+
+// an attachment announced by a client, its content follows in binary frames
+#[derive(Debug)]
+pub struct Upload {
+    pub room: String,
+    pub text: String,
+    pub name: String,
+    pub mime: String,
+    pub size: usize,
+    data: Vec<u8>,
+}
+
+impl Upload {
+    // check an announced attachment against the limits before accepting its content
+    pub fn new(
+        room: String,
+        text: String,
+        name: String,
+        mime: String,
+        size: usize,
+        limits: &AttachmentLimits,
+    ) -> Result<Self, String> {
+        if name.is_empty()
+            || name.chars().count() > MAX_NAME_LENGTH
+            || name
+                .chars()
+                .any(|c| c.is_control() || content::is_format(c) || c == '/' || c == '\\')
+        {
+            return Err(format!("Invalid file name: {}", name));
+        }
+
+        if !limits.types.contains(&mime) {
+            return Err(format!("Attachments of type {} are not allowed", mime));
+        }
+
+        if size == 0 || size > limits.max_size {
+            return Err(format!(
+                "Attachments should contain 1 to {} bytes",
+                limits.max_size
+            ));
+        }
+
+        Ok(Upload {
+            room,
+            text,
+            name,
+            mime,
+            size,
+            // grown as chunks arrive, the announced size is not reserved up front
+            data: Vec::new(),
+        })
+    }
+
+    // add a chunk of the content, returns whether the upload is complete
+    pub fn append(&mut self, chunk: &[u8]) -> Result<bool, String> {
+        if self.data.len() + chunk.len() > self.size {
+            return Err(format!("Attachment is larger than {} bytes", self.size));
+        }
+        self.data.extend_from_slice(chunk);
+
+        Ok(self.data.len() == self.size)
+    }
+
+    // the received content, when it matches the announced type
+    pub fn finish(self) -> Result<(Attachment, Vec<u8>), String> {
+        if !has_signature(&self.mime, &self.data) {
+            return Err(format!("Attachment is not a valid {} file", self.mime));
+        }
+
+        let attachment = Attachment {
+            name: self.name,
+            mime: self.mime,
+            size: self.size,
+            hash: hash(&self.data),
+        };
+
+        Ok((attachment, self.data))
+    }
+}
+
+// hex encoded SHA-256 of some content
+pub fn hash(data: &[u8]) -> String {
+    digest::digest(&digest::SHA256, data)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// check the magic bytes of common file types, so images can not be something else in disguise,
+// types without a known signature are accepted as is
+fn has_signature(mime: &str, data: &[u8]) -> bool {
+    match mime {
+        "image/png" => data.starts_with(b"\x89PNG\r\n\x1a\n"),
+        "image/jpeg" => data.starts_with(b"\xff\xd8\xff"),
+        "image/gif" => data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a"),
+        "image/webp" => data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP",
+        "application/pdf" => data.starts_with(b"%PDF-"),
+        _ => true,
+    }
+}
+
+// storage backend for the content of attachments, addressed by their hash
+pub trait AttachmentStore: Send + Sync {
+    // store content under its hash, storing the same content again has no effect
+    fn put(&self, hash: &str, data: &[u8]) -> Result<(), Error>;
+
+    // retrieve the content with a hash
+    fn get(&self, hash: &str) -> Result<Option<Vec<u8>>, Error>;
+}
+
+// attachments that only live as long as the application, used for testing
+#[derive(Debug, Default)]
+pub struct MemoryAttachments {
+    files: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl AttachmentStore for MemoryAttachments {
+    fn put(&self, hash: &str, data: &[u8]) -> Result<(), Error> {
+        self.files
+            .lock()
+            .unwrap()
+            .entry(hash.to_string())
+            .or_insert_with(|| data.to_vec());
+
+        Ok(())
+    }
+
+    fn get(&self, hash: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.files.lock().unwrap().get(hash).cloned())
+    }
+}
+
+// attachments stored as files named after their hash in a directory
+pub struct FileAttachments {
+    dir: PathBuf,
+}
+
+impl FileAttachments {
+    // use (or create) the directory at the given path
+    pub fn open(path: &str) -> Result<Self, Error> {
+        fs::create_dir_all(path)?;
+
+        Ok(FileAttachments {
+            dir: PathBuf::from(path),
+        })
+    }
+
+    // hashes are hex encoded, anything else could escape the directory
+    fn path(&self, hash: &str) -> Result<PathBuf, Error> {
+        if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::Parse(format!("Invalid attachment hash: {}", hash)));
+        }
+
+        Ok(self.dir.join(hash))
+    }
+}
+
+impl AttachmentStore for FileAttachments {
+    fn put(&self, hash: &str, data: &[u8]) -> Result<(), Error> {
+        let path = self.path(hash)?;
+        if path.exists() {
+            return Ok(());
+        }
+
+        // write to a temporary file first, so a file with a hash name is always complete
+        let partial = path.with_extension("partial");
+        fs::write(&partial, data)?;
+        fs::rename(&partial, &path)?;
+
+        Ok(())
+    }
+
+    fn get(&self, hash: &str) -> Result<Option<Vec<u8>>, Error> {
+        match fs::read(self.path(hash)?) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
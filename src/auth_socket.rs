@@ -1,14 +1,23 @@
+use crate::badges;
+use crate::chat_room::{self, Requirement};
+use crate::config;
 use crate::errors::Error;
-use crate::irma::SessionStatus;
+use crate::heartbeat::{Heartbeat, Liveness};
+use crate::irma::{self, ConDisCon, IrmaAttributes, SessionStatus};
 use crate::irma_session::IrmaSession;
+use crate::pseudonym;
+use crate::roles;
 use crate::session_jwt::SessionJwt;
-use crate::socket_request::SocketRequest;
+use crate::shutdown::{self, ShutdownSignal};
+use crate::socket_request::{AuthRequest, SocketRequest};
 use crate::socket_response::SocketResponse;
 use futures_core::stream::Stream;
 use futures_util::future::{self, Ready};
 use futures_util::stream::SplitSink;
 use futures_util::{self, SinkExt, StreamExt};
+use std::collections::BTreeMap;
 use std::pin::Pin;
+use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::WebSocketStream;
@@ -25,8 +34,11 @@ impl AuthSession {
         let ws_stream = tokio_tungstenite::accept_async(stream).await?;
         let (write, read) = ws_stream.split();
         let read = read.filter_map(|message| -> Ready<Option<SocketRequest>> {
-            info!("Received ws message from the client: {:?}", message);
-            future::ready(SocketRequest::from_message(message.into()).ok())
+            let request = SocketRequest::from_message(message.into()).ok();
+            if let Some(request) = &request {
+                info!("Received a {} message from the client", request.action());
+            }
+            future::ready(request)
         });
 
         Ok(AuthSession {
@@ -37,20 +49,95 @@ impl AuthSession {
 
     // send a SocketResponse back to the client
     pub async fn send(&mut self, response: SocketResponse) -> Result<(), Error> {
-        info!("Sending ws message to the client: {:?}", response);
+        info!("Sending a {} message to the client", response.action());
         self.write.send(response.encode()?.into()).await?;
 
         Ok(())
     }
+
+    // check whether the client is still there, it should answer with a pong
+    pub async fn ping(&mut self) -> Result<(), Error> {
+        self.write.send(Message::Ping(Vec::new())).await?;
+
+        Ok(())
+    }
+
+    // end the connection with the client
+    pub async fn close(&mut self) -> Result<(), Error> {
+        self.write.send(Message::Close(None)).await?;
+
+        Ok(())
+    }
+
+    // end the connection because the server shuts down, the client may start a new session later
+    pub async fn go_away(&mut self) -> Result<(), Error> {
+        self.write.send(shutdown::going_away()).await?;
+
+        Ok(())
+    }
+}
+
+// the disclosed attributes that give access to gated rooms
+fn gated_attributes(disclosed: &IrmaAttributes) -> BTreeMap<String, String> {
+    let gated = chat_room::gated_attributes();
+
+    disclosed
+        .iter()
+        .filter(|(attribute, _)| gated.contains(*attribute))
+        .map(|(attribute, value)| (attribute.clone(), value.clone()))
+        .collect()
+}
+
+// the session that results from an authentication session
+enum Profile {
+    // a new session identified by the disclosed name
+    Identified,
+    // a new session under a pseudonym, optionally with a picked name
+    Pseudonymous(Option<String>),
+    // an existing session with additional attributes
+    Disclose(SessionJwt),
+}
+
+// check a typed request to start an authentication session, returns the attributes to request
+fn start_request(request: &SocketRequest) -> Result<(ConDisCon, Profile), Error> {
+    match request.auth_request()? {
+        AuthRequest::Pseudonymous { pseudonym } => {
+            if let Some(name) = &pseudonym {
+                if !pseudonym::is_valid_name(name) {
+                    return Err(Error::Parse(format!("Invalid pseudonym: {}", name)));
+                }
+            }
+
+            let disclosure = IrmaSession::pseudonymous_disclosure()?;
+            Ok((disclosure, Profile::Pseudonymous(pseudonym)))
+        }
+        AuthRequest::Disclose { token, attributes } => {
+            let jwt = SessionJwt::from_jwt(token)?;
+
+            // only attributes that give access to rooms can be added to a session
+            let gated = chat_room::gated_attributes();
+            if attributes.is_empty() || attributes.iter().any(|r| !gated.contains(&r.attribute)) {
+                return Err(Error::Parse(
+                    "Only attributes of gated rooms can be disclosed".to_string(),
+                ));
+            }
+
+            let requested = attributes.iter().map(Requirement::disclosure).collect();
+            let disclosure = IrmaSession::session_disclosure(&jwt.sub, requested)?;
+            Ok((disclosure, Profile::Disclose(jwt)))
+        }
+    }
 }
 
-// verify the proof at the end of an IRMA session and send the resulting proof JWT
+// verify the proof at the end of an IRMA session and send the resulting session JWT, either
+// a new session or an existing session with additional attributes
 async fn finish_session(
     irma_session: &IrmaSession,
     auth_session: &mut AuthSession,
+    profile: Profile,
 ) -> Result<(), Error> {
-    // retrieve a username from a IRMA proof
-    let username = match irma_session.get_proof_payload().await {
+    // retrieve the disclosed attributes from a IRMA proof
+    let proof = match irma_session.get_proof_payload().await {
         Ok(claim) => claim,
         Err(e) => {
             error!("Could not verify claim: {:?}", e);
@@ -61,8 +148,58 @@ async fn finish_session(
         }
     };
 
-    // create a application signed JWT containing the username for chat
-    let jwt = SessionJwt::new(username).as_jwt()?;
+    let attributes = &proof.attributes;
+    let disclosed = badges::from_disclosure(attributes.keys(), proof.iat);
+
+    let jwt = match profile {
+        // the existing session keeps its id and lifetime, so it can still be revoked
+        Profile::Disclose(mut jwt) => {
+            // the proof should come from the owner of the session, not just anyone with the
+            // requested attributes
+            let owner = if pseudonym::is_pseudonym(&jwt.sub) {
+                pseudonym::belongs_to(&jwt.sub, attributes)?
+            } else {
+                irma::username(attributes)? == jwt.sub
+            };
+            if !owner {
+                warn!("Disclosed attributes do not belong to {}", &jwt.sub);
+                let error =
+                    SocketResponse::error("Disclosure does not match the session".to_string());
+                auth_session.send(error).await?;
+
+                return Err(Error::IdentityMismatch);
+            }
+
+            info!("Added disclosed attributes to the session of {}", &jwt.sub);
+            jwt.attributes.extend(gated_attributes(attributes));
+            badges::merge(&mut jwt.badges, disclosed);
+            jwt
+        }
+        // create a application signed JWT containing the username and roles for chat
+        Profile::Identified => {
+            let username = irma::username(attributes)?;
+            let roles = roles::granted(attributes);
+            if !roles.is_empty() {
+                info!("Granted roles {:?} to {}", roles, username);
+            }
+
+            let mut jwt = SessionJwt::with_roles(username, roles);
+            jwt.attributes = gated_attributes(attributes);
+            jwt.badges = disclosed;
+            jwt
+        }
+        // the identifying attributes are only used to derive the pseudonym, never shown
+        Profile::Pseudonymous(name) => {
+            let pseudonym = pseudonym::pseudonym(name.as_deref(), attributes)?;
+            info!("Started a pseudonymous session for {}", pseudonym);
+
+            let mut jwt = SessionJwt::new(pseudonym);
+            jwt.attributes = gated_attributes(attributes);
+            jwt.badges = disclosed;
+            jwt
+        }
+    };
+    let jwt = jwt.as_jwt()?;
     let action = SocketResponse::jwt(jwt);
     auth_session.send(action).await?;
 
@@ -70,30 +207,74 @@ async fn finish_session(
 }
 
 // handle new authentication ws connections
-async fn accept_auth_connection(stream: TcpStream) -> Result<(), Error> {
+async fn accept_auth_connection(
+    stream: TcpStream,
+    mut shutdown: ShutdownSignal,
+) -> Result<(), Error> {
     info!("New ws auth connection");
     let mut auth_session = AuthSession::new(stream).await?;
-    let msg = auth_session.read.next().await;
 
-    // we expect the first message to be 'start' (to start an IRMA session)
-    if msg.is_none() || !msg.unwrap().is_start() {
-        error!("Expected the first message to be 'start'");
-        return Ok(());
-    }
+    // clients that do not start a session are disconnected
+    let start_timeout = Duration::from_secs(config::get_parsed_or("WS_START_TIMEOUT", 30));
+    let msg = tokio::select! {
+        msg = tokio::time::timeout(start_timeout, auth_session.read.next()) => match msg {
+            Ok(msg) => msg,
+            Err(_) => {
+                warn!("Authentication session was not started in time");
+                auth_session.close().await?;
+
+                return Ok(());
+            }
+        },
+        _ = shutdown.recv() => {
+            auth_session.go_away().await?;
+
+            return Ok(());
+        },
+    };
+
+    let request = match msg {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+
+    // we expect the first message to be 'start' (to start an IRMA session), or a request to
+    // start a pseudonymous session or disclose additional attributes for an existing session
+    let (disclosure, profile) = if request.is_start() {
+        (IrmaSession::login_disclosure()?, Profile::Identified)
+    } else {
+        match start_request(&request) {
+            Ok(started) => started,
+            Err(e) => {
+                error!("Expected the first message to be 'start': {:?}", e);
+                let error = SocketResponse::error("Invalid request".to_string());
+                auth_session.send(error).await?;
+
+                return Ok(());
+            }
+        }
+    };
 
     // create a new IRMA session
     info!("Starting new IRMA session");
-    let irma_session = IrmaSession::new().await?;
+    let irma_session = IrmaSession::new(disclosure).await?;
     let qr = SocketResponse::qr(irma_session.qr.clone());
     auth_session.send(qr).await?;
 
     // subscribe to updates from the IRMA server
     let mut upstream = irma_session.get_updates().await?;
 
+    // the client is pinged while waiting for the IRMA session
+    let heartbeat = Heartbeat::from_env();
+    let liveness = Liveness::default();
+    let mut pings = heartbeat.pings();
+
     // wait for either updates from the IRMA server of messages from the client
     loop {
         tokio::select! {
             Some(request) = auth_session.read.next() => {
+                liveness.seen();
+
                 // stop the session when a connection is closed a a session in canceled
                 if request.is_close() || request.is_stop() {
                     warn!("Authentication session canceled");
@@ -115,11 +296,26 @@ async fn accept_auth_connection(stream: TcpStream) -> Result<(), Error> {
 
                 if status == SessionStatus::Done {
                     info!("Authentication session done, sending JWT");
-                    finish_session(&irma_session, &mut auth_session).await?;
+                    finish_session(&irma_session, &mut auth_session, profile).await?;
                     break;
                 }
-            }
-            else => break,
+            },
+            _ = pings.tick() => {
+                // stop the session when the client disappeared without closing the connection
+                if heartbeat.is_dead(&liveness) {
+                    warn!("Authentication session client does not answer pings");
+                    irma_session.stop().await?;
+                    break;
+                }
+
+                auth_session.ping().await?;
+            },
+            _ = shutdown.recv() => {
+                warn!("Authentication session canceled, the server shuts down");
+                irma_session.stop().await?;
+                auth_session.go_away().await?;
+                break;
+            },
         }
     }
 
@@ -128,8 +324,8 @@ async fn accept_auth_connection(stream: TcpStream) -> Result<(), Error> {
 }
 
 // handle a ws connection, print possible errors
-pub async fn handle_auth_connection(stream: TcpStream) {
-    if let Err(e) = accept_auth_connection(stream).await {
+pub async fn handle_auth_connection(stream: TcpStream, shutdown: ShutdownSignal) {
+    if let Err(e) = accept_auth_connection(stream, shutdown).await {
         error!("Error during authentication session: {:?}", e);
     }
 }
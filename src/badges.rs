@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+
+// a credential the sender of a message disclosed, without the disclosed values,
+// i.e. {"credential":"pbdf.pbdf.idin","issuer":"pbdf.pbdf","attributes":["initials"],...}
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Badge {
+    // the type of the credential, i.e. "pbdf.gemeente.personalData"
+    pub credential: String,
+    // the issuer of the credential, i.e. "pbdf.gemeente"
+    pub issuer: String,
+    // the names of the disclosed attributes of the credential, i.e. ["over18"]
+    pub attributes: Vec<String>,
+    // the time the IRMA server verified the disclosure
+    pub verified: i64,
+}
+
+// group disclosed attribute ids (scheme.issuer.credential.attribute) by credential
+pub fn from_disclosure<'a>(
+    attributes: impl Iterator<Item = &'a String>,
+    verified: i64,
+) -> Vec<Badge> {
+    let mut badges: Vec<Badge> = Vec::new();
+
+    for id in attributes {
+        let parts: Vec<&str> = id.split('.').collect();
+        if parts.len() != 4 {
+            continue;
+        }
+
+        let credential = parts[..3].join(".");
+        match badges
+            .iter_mut()
+            .find(|badge| badge.credential == credential)
+        {
+            Some(badge) => badge.attributes.push(parts[3].to_string()),
+            None => badges.push(Badge {
+                credential,
+                issuer: parts[..2].join("."),
+                attributes: vec![parts[3].to_string()],
+                verified,
+            }),
+        }
+    }
+
+    for badge in badges.iter_mut() {
+        badge.attributes.sort();
+    }
+    badges.sort_by(|a, b| a.credential.cmp(&b.credential));
+
+    badges
+}
+
+// add the badges of a later disclosure, credentials that were disclosed before are updated
+pub fn merge(badges: &mut Vec<Badge>, disclosed: Vec<Badge>) {
+    for badge in disclosed {
+        match badges.iter_mut().find(|b| b.credential == badge.credential) {
+            Some(existing) => {
+                for attribute in badge.attributes {
+                    if !existing.attributes.contains(&attribute) {
+                        existing.attributes.push(attribute);
+                    }
+                }
+                existing.attributes.sort();
+                existing.verified = existing.verified.max(badge.verified);
+            }
+            None => badges.push(badge),
+        }
+    }
+
+    badges.sort_by(|a, b| a.credential.cmp(&b.credential));
+}
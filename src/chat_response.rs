@@ -0,0 +1,293 @@
+use crate::attachments::Attachment;
+use crate::badges::Badge;
+use crate::chat_room::{Requirement, RoomInfo};
+use crate::client_queue::QueueStats;
+use crate::content::Rejection;
+use crate::errors::Error;
+use crate::message_store::{Reaction, StoredMessage};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+// maximum number of characters of a quoted parent message
+const QUOTE_LENGTH: usize = 100;
+
+// a short excerpt of the message that is replied to
+#[derive(Serialize, Debug, Clone)]
+pub struct Quote {
+    pub id: i64,
+    pub user: String,
+    pub msg: String,
+}
+
+impl Quote {
+    // quote the start of a message in the history
+    pub fn from_stored(message: &StoredMessage) -> Self {
+        let mut msg: String = message.msg.chars().take(QUOTE_LENGTH).collect();
+        if msg.len() < message.msg.len() {
+            msg.push('…');
+        }
+
+        Quote {
+            id: message.id,
+            user: message.user.clone(),
+            msg,
+        }
+    }
+}
+
+// a chat message in a room, or a direct message to a user
+#[derive(Serialize, Debug, Clone)]
+pub struct ChatMessage {
+    pub id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    pub user: String,
+    pub time: i64,
+    pub its_me: bool,
+    pub msg: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edited: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quote: Option<Quote>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<Reaction>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub badges: Vec<Badge>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<Box<Attachment>>,
+}
+
+impl ChatMessage {
+    // create a chat message from a message in the history, quoting the message it replies to
+    pub fn from_stored(
+        message: &StoredMessage,
+        its_me: bool,
+        parent: Option<&StoredMessage>,
+    ) -> Self {
+        ChatMessage {
+            id: message.id,
+            room: Some(message.room.clone()),
+            to: None,
+            user: message.user.clone(),
+            time: message.time,
+            its_me,
+            msg: message.msg.clone(),
+            edited: message.edited,
+            reply_to: message.reply_to,
+            quote: parent.map(Quote::from_stored),
+            reactions: message.reactions.clone(),
+            badges: message.badges.clone(),
+            attachment: message.attachment.clone().map(Box::new),
+        }
+    }
+}
+
+// a page of the message history of a room, the cursor can be used to retrieve older messages
+#[derive(Serialize, Debug, Clone)]
+pub struct HistoryPage {
+    pub room: String,
+    pub messages: Vec<ChatMessage>,
+    pub cursor: Option<i64>,
+}
+
+// a message and all replies to it
+#[derive(Serialize, Debug, Clone)]
+pub struct ThreadPage {
+    pub room: String,
+    pub id: i64,
+    pub messages: Vec<ChatMessage>,
+}
+
+// whether a user arrived in or left a room
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceAction {
+    Join,
+    Leave,
+}
+
+// a user joined or left a room, multiple connections of the same user count as one presence
+#[derive(Serialize, Debug, Clone)]
+pub struct PresenceEvent {
+    pub room: String,
+    pub user: String,
+    pub action: PresenceAction,
+    pub time: i64,
+}
+
+// machine readable reason of a rejected request
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Authentication,
+    InvalidRequest,
+    UnsupportedVersion,
+    InvalidRoom,
+    NotAMember,
+    UserOffline,
+    NotFound,
+    Forbidden,
+    RateLimited,
+    Muted,
+    TooManyDevices,
+    MissingAttributes,
+    InvalidAttachment,
+    InvalidContent,
+    AmbiguousMention,
+    Storage,
+}
+
+// a rejected request, with a human readable explanation
+#[derive(Serialize, Debug, Clone)]
+pub struct ChatError {
+    pub code: ErrorCode,
+    pub message: String,
+    // the attributes that should be disclosed before the request can succeed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub missing: Vec<Requirement>,
+    // why the text of a message was rejected
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub rejection: Option<Rejection>,
+    // the users a mentioned name could refer to
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub candidates: Vec<String>,
+}
+
+impl ChatError {
+    pub fn new(code: ErrorCode, message: String) -> Self {
+        ChatError {
+            code,
+            message,
+            missing: Vec::new(),
+            rejection: None,
+            candidates: Vec::new(),
+        }
+    }
+
+    // a room can only be joined after disclosing more attributes
+    pub fn missing_attributes(room: &str, missing: Vec<Requirement>) -> Self {
+        ChatError {
+            missing,
+            ..ChatError::new(
+                ErrorCode::MissingAttributes,
+                format!("Additional attributes are required to join room: {}", room),
+            )
+        }
+    }
+
+    // a mentioned name matches multiple users, they should be mentioned by their full name
+    pub fn ambiguous_mention(name: &str, candidates: Vec<String>) -> Self {
+        ChatError {
+            candidates,
+            ..ChatError::new(
+                ErrorCode::AmbiguousMention,
+                format!("@{} could refer to multiple users", name),
+            )
+        }
+    }
+}
+
+impl From<Rejection> for ChatError {
+    // explain why the text of a message was rejected
+    fn from(rejection: Rejection) -> Self {
+        let message = match rejection {
+            Rejection::Empty => "Message is empty".to_string(),
+            Rejection::TooLong { max_length } => {
+                format!("Message is longer than {} characters", max_length)
+            }
+        };
+
+        ChatError {
+            rejection: Some(rejection),
+            ..ChatError::new(ErrorCode::InvalidContent, message)
+        }
+    }
+}
+
+impl From<Error> for ChatError {
+    // explain why a client message could not be parsed
+    fn from(err: Error) -> Self {
+        match err {
+            Error::UnsupportedVersion(v) => ChatError::new(
+                ErrorCode::UnsupportedVersion,
+                format!("Unsupported protocol version: {}", v),
+            ),
+            Error::Serialization(e) => {
+                ChatError::new(ErrorCode::InvalidRequest, format!("Invalid request: {}", e))
+            }
+            e => ChatError::new(
+                ErrorCode::InvalidRequest,
+                format!("Invalid request: {:?}", e),
+            ),
+        }
+    }
+}
+
+// typed message from the server to a chat client, i.e. {"type":"message","room":"general",...}
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatResponse {
+    Message(ChatMessage),
+    Edit {
+        room: String,
+        id: i64,
+        msg: String,
+        edited: i64,
+    },
+    Delete {
+        room: String,
+        id: i64,
+    },
+    Reactions {
+        room: String,
+        id: i64,
+        reactions: Vec<Reaction>,
+    },
+    Presence(PresenceEvent),
+    Online {
+        room: String,
+        users: Vec<String>,
+    },
+    Typing {
+        room: String,
+        user: String,
+        typing: bool,
+    },
+    History(HistoryPage),
+    Thread(ThreadPage),
+    Search(HistoryPage),
+    // a message mentioning the user, in any room
+    Mention(ChatMessage),
+    // precedes the binary frames with the content of an attachment
+    Download {
+        id: i64,
+        attachment: Attachment,
+        chunks: usize,
+    },
+    Rooms {
+        rooms: Vec<RoomInfo>,
+    },
+    Pong,
+    Metrics(QueueStats),
+    Warning(ChatError),
+    Error(ChatError),
+}
+
+impl ChatResponse {
+    // encode a response to a ws message (as json)
+    pub fn to_message(&self) -> Message {
+        let encoded_response = serde_json::to_string(self).unwrap();
+
+        encoded_response.into()
+    }
+
+    // encode a response once, to be shared by all its recipients
+    pub fn to_shared(&self) -> Arc<str> {
+        serde_json::to_string(self).unwrap().into()
+    }
+}
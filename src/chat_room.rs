@@ -0,0 +1,89 @@
+use crate::config;
+use crate::irma::{Attribute, SpecificAttribute};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// summary of a chat room, as shown in the room list
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RoomInfo {
+    pub name: String,
+    pub members: usize,
+    pub joined: bool,
+}
+
+// the room every client joins when connecting
+pub fn default_room() -> String {
+    config::get_or("CHAT_DEFAULT_ROOM", "general")
+}
+
+// room names are short identifiers, i.e. "general" or "rust-nl"
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// an attribute the members of a room must have disclosed, optionally with a specific value,
+// i.e. {"attribute":"pbdf.gemeente.personalData.over18","value":"Yes"}
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Requirement {
+    pub attribute: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+impl Requirement {
+    // check whether the disclosed attributes satisfy this requirement
+    pub fn is_met(&self, attributes: &BTreeMap<String, String>) -> bool {
+        match (attributes.get(&self.attribute), &self.value) {
+            (Some(disclosed), Some(value)) => disclosed == value,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    // the IRMA disclosure that satisfies this requirement
+    pub fn disclosure(&self) -> Vec<Vec<Attribute>> {
+        let attribute = match &self.value {
+            Some(value) => Attribute::Specific(SpecificAttribute::new(&self.attribute, value)),
+            None => Attribute::Simple(self.attribute.clone()),
+        };
+
+        vec![vec![attribute]]
+    }
+}
+
+// the requirements of all gated rooms,
+// i.e. '{"adults":[{"attribute":"pbdf.gemeente.personalData.over18","value":"Yes"}]}'
+fn gates() -> HashMap<String, Vec<Requirement>> {
+    serde_json::from_str(&config::get_or("CHAT_ROOMS", "{}"))
+        .unwrap_or_else(|_| panic!("Fatal: the enviroment variable CHAT_ROOMS is invalid."))
+}
+
+// the attributes that are required by any of the gated rooms
+pub fn gated_attributes() -> BTreeSet<String> {
+    gates()
+        .into_values()
+        .flatten()
+        .map(|requirement| requirement.attribute)
+        .collect()
+}
+
+// whether some attributes are required to join a room
+pub fn is_gated(room: &str) -> bool {
+    gates()
+        .get(room)
+        .is_some_and(|requirements| !requirements.is_empty())
+}
+
+// the requirements of a room that are not met by the disclosed attributes
+pub fn missing_requirements(room: &str, attributes: &BTreeMap<String, String>) -> Vec<Requirement> {
+    gates()
+        .remove(room)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|requirement| !requirement.is_met(attributes))
+        .collect()
+}
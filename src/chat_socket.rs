@@ -1,133 +1,1570 @@
 use crate::errors::Error;
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    convert::TryFrom,
     net::SocketAddr,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-use crate::config;
-use crate::jwt::decode;
+use crate::attachments::{self, Attachment, AttachmentStore, MemoryAttachments, Upload};
+use crate::chat_response::{
+    ChatError, ChatMessage, ChatResponse, ErrorCode, HistoryPage, PresenceAction, PresenceEvent,
+    ThreadPage,
+};
+use crate::chat_room::{self, RoomInfo};
+use crate::client_queue::{self, ClientQueue, QueueMetrics};
+use crate::config::ChatConfig;
+use crate::content::{self, Rejection};
+use crate::fanout::{Member, RoomDirectory};
+use crate::heartbeat::{Heartbeat, Liveness};
+use crate::mentions;
+use crate::message_id::MessageIds;
+use crate::message_store::{self, MemoryStore, MessageStore, StoredMessage};
+use crate::pseudonym;
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::revocation::RevocationList;
+use crate::roles::Role;
+use crate::search::SearchQuery;
 use crate::session_jwt::SessionJwt;
-use crate::socket_request::SocketRequest;
+use crate::shutdown::{self, ShutdownSignal};
+use crate::socket_request::{ChatRequest, SocketRequest};
+use crate::typing::{TypingExpiry, TypingState, TypingUpdate};
 use chrono::Utc;
-use futures_channel::mpsc::{unbounded, UnboundedSender};
-use futures_util::{self, future, pin_mut, stream::TryStreamExt, SinkExt, StreamExt};
+use futures_util::{self, future, SinkExt, StreamExt};
 use log::error;
-use serde::Serialize;
-use serde_json::json;
 use tokio::net::TcpStream;
-use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Message, WebSocketConfig};
+
+// maximum size of a single frame from a client, attachments are uploaded in multiple frames
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
 
+// a single websocket connection, i.e. one of the devices of a user
 #[derive(Debug)]
+pub struct Connection {
+    rooms: HashSet<String>,
+    tx: ClientQueue,
+    // the attachment the client is sending in binary frames
+    upload: Option<Upload>,
+}
+
+// all connections of a user
+#[derive(Debug, Default)]
 pub struct ChatClient {
-    user: String,
-    tx: UnboundedSender<Message>,
+    connections: HashMap<SocketAddr, Connection>,
+}
+
+impl ChatClient {
+    // whether any of the connections of the user joined a room
+    fn in_room(&self, room: &str) -> bool {
+        self.connections
+            .values()
+            .any(|connection| connection.rooms.contains(room))
+    }
+}
+
+// in memory administration of connected clients, grouped by subject
+pub type PeerMap = Arc<Mutex<HashMap<String, ChatClient>>>;
+
+// look up a single connection of a user
+fn connection<'a>(
+    peers: &'a HashMap<String, ChatClient>,
+    user: &str,
+    addr: &SocketAddr,
+) -> Option<&'a Connection> {
+    peers.get(user)?.connections.get(addr)
+}
+
+// state shared by all chat connections
+#[derive(Clone)]
+pub struct ChatState {
+    pub peers: PeerMap,
+    pub directory: Arc<RoomDirectory>,
+    pub revocations: Arc<Mutex<RevocationList>>,
+    pub store: Arc<dyn MessageStore>,
+    pub attachments: Arc<dyn AttachmentStore>,
+    pub ids: Arc<MessageIds>,
+    // the subjects that wrote a message, which can be mentioned in all open rooms
+    pub authors: Arc<Mutex<BTreeSet<String>>>,
+    pub typing: Arc<Mutex<TypingState>>,
+    pub limiter: Arc<Mutex<RateLimiter>>,
+    pub metrics: Arc<QueueMetrics>,
+    pub config: ChatConfig,
+}
+
+impl ChatState {
+    // create the chat state, keeping the message history and attachments in the given stores
+    pub fn new(
+        store: Arc<dyn MessageStore>,
+        attachments: Arc<dyn AttachmentStore>,
+        config: ChatConfig,
+    ) -> Self {
+        let last_id = store
+            .last_id()
+            .expect("Failed to read the message store")
+            .unwrap_or(0);
+        let authors = store.authors().expect("Failed to read the message store");
+
+        ChatState {
+            peers: PeerMap::default(),
+            directory: Arc::default(),
+            revocations: Arc::default(),
+            store,
+            attachments,
+            ids: Arc::new(MessageIds::starting_after(last_id)),
+            authors: Arc::new(Mutex::new(authors.into_iter().collect())),
+            typing: Arc::default(),
+            limiter: Arc::default(),
+            metrics: Arc::default(),
+            config,
+        }
+    }
+}
+
+impl Default for ChatState {
+    // chat state with a message history and attachments that are kept in memory
+    fn default() -> Self {
+        ChatState::new(
+            Arc::new(MemoryStore::default()),
+            Arc::new(MemoryAttachments::default()),
+            ChatConfig::from_env(),
+        )
+    }
+}
+
+// make sure the user was granted a role before it performs a privileged action
+fn check_role(jwt: &SessionJwt, role: Role, action: &str) -> Result<(), ChatError> {
+    if !jwt.has_role(role) {
+        warn!("{} is not allowed to {}", &jwt.sub, action);
+        return Err(ChatError::new(
+            ErrorCode::Forbidden,
+            format!("Not allowed to {}", action),
+        ));
+    }
+
+    Ok(())
+}
+
+// the connected subjects that belong to the same person as a subject, i.e. all pseudonyms
+// with the same stable id
+fn same_person(peers: &HashMap<String, ChatClient>, sub: &str) -> Vec<String> {
+    let id = pseudonym::stable_id(sub);
+    peers
+        .keys()
+        .filter(|user| pseudonym::stable_id(user) == id)
+        .cloned()
+        .collect()
+}
+
+// close all connections of a subject, i.e. after a logout
+fn disconnect_subject(state: &ChatState, sub: &str, reason: &'static str) {
+    let mut peers = state.peers.lock().unwrap();
+    disconnect_users(&mut peers, &state.directory, &[sub.to_string()], reason);
+}
+
+// close all connections of the person behind a subject, including its other pseudonyms, i.e.
+// after a revocation, kick or ban
+fn disconnect_person(state: &ChatState, sub: &str, reason: &'static str) {
+    let mut peers = state.peers.lock().unwrap();
+    let users = same_person(&peers, sub);
+    disconnect_users(&mut peers, &state.directory, &users, reason);
+}
+
+// close all connections of the given users
+fn disconnect_users(
+    peers: &mut HashMap<String, ChatClient>,
+    directory: &RoomDirectory,
+    users: &[String],
+    reason: &'static str,
+) {
+    for user in users {
+        let addrs: Vec<SocketAddr> = match peers.get(user) {
+            Some(client) => client.connections.keys().copied().collect(),
+            None => continue,
+        };
+
+        for addr in addrs {
+            if let Some(connection) = remove_peer(peers, directory, user, &addr) {
+                let close = Message::Close(Some(CloseFrame {
+                    code: CloseCode::Normal,
+                    reason: reason.into(),
+                }));
+                connection.tx.send(close);
+            }
+        }
+    }
+}
+
+// the users that have at least one connection in a room
+fn room_users(peers: &HashMap<String, ChatClient>, room: &str) -> BTreeSet<String> {
+    peers
+        .iter()
+        .filter(|(_, client)| client.in_room(room))
+        .map(|(user, _)| user.clone())
+        .collect()
+}
+
+// send a response to all connections in a room, except those of the user it is about
+fn notify_others(directory: &RoomDirectory, room: &str, user: &str, response: ChatResponse) {
+    directory.send(room, &response.to_shared(), |member| member.user != user);
+}
+
+// tell the other members of a room that a user joined or left
+fn announce_presence(directory: &RoomDirectory, room: &str, user: &str, action: PresenceAction) {
+    let response = ChatResponse::Presence(PresenceEvent {
+        room: room.to_string(),
+        user: user.to_string(),
+        action,
+        time: Utc::now().timestamp(),
+    });
+
+    notify_others(directory, room, user, response);
+}
+
+// tell the other members of a room that a user started or stopped typing
+fn relay_typing(directory: &RoomDirectory, room: &str, user: &str, typing: bool) {
+    let response = ChatResponse::Typing {
+        room: room.to_string(),
+        user: user.to_string(),
+        typing,
+    };
+
+    notify_others(directory, room, user, response);
+}
+
+// relay that a user stopped typing when it did not refresh its typing indicator in time,
+// i.e. because the client disappeared
+async fn expire_typing(state: ChatState, room: String, user: String) {
+    loop {
+        let expiry = state
+            .typing
+            .lock()
+            .unwrap()
+            .expire(&room, &user, state.config.typing_timeout);
+
+        match expiry {
+            TypingExpiry::Pending(deadline) => {
+                tokio::time::sleep_until(deadline.into()).await;
+            }
+            TypingExpiry::Expired => {
+                relay_typing(&state.directory, &room, &user, false);
+                break;
+            }
+            TypingExpiry::Done => break,
+        }
+    }
+}
+
+// remove a connection from a room, announcing when the user's last connection left
+fn leave_room(
+    peers: &mut HashMap<String, ChatClient>,
+    directory: &RoomDirectory,
+    user: &str,
+    addr: &SocketAddr,
+    room: &str,
+) {
+    let client = match peers.get_mut(user) {
+        Some(client) => client,
+        None => return,
+    };
+
+    let left = match client.connections.get_mut(addr) {
+        Some(connection) => connection.rooms.remove(room),
+        None => false,
+    };
+
+    if !left {
+        return;
+    }
+    directory.leave(room, addr);
+
+    if !client.in_room(room) {
+        announce_presence(directory, room, user, PresenceAction::Leave);
+    }
+}
+
+// remove a connection from the administration, leaving all its rooms
+fn remove_peer(
+    peers: &mut HashMap<String, ChatClient>,
+    directory: &RoomDirectory,
+    user: &str,
+    addr: &SocketAddr,
+) -> Option<Connection> {
+    let rooms: Vec<String> = connection(peers, user, addr)?
+        .rooms
+        .iter()
+        .cloned()
+        .collect();
+
+    for room in rooms {
+        leave_room(peers, directory, user, addr, &room);
+    }
+
+    let client = peers.get_mut(user)?;
+    let connection = client.connections.remove(addr);
+    if client.connections.is_empty() {
+        peers.remove(user);
+    }
+
+    connection
+}
+
+// send a response to a single connection
+fn reply(peer_map: &PeerMap, user: &str, addr: &SocketAddr, response: ChatResponse) {
+    if let Some(connection) = connection(&peer_map.lock().unwrap(), user, addr) {
+        connection.tx.send(response.to_message());
+    }
+}
+
+// send a response to all connections of a user
+fn notify_user(peer_map: &PeerMap, user: &str, response: ChatResponse) {
+    if let Some(client) = peer_map.lock().unwrap().get(user) {
+        let frame = response.to_shared();
+        for connection in client.connections.values() {
+            connection.tx.send(frame.clone());
+        }
+    }
+}
+
+// send a response to all connections of the person behind a subject, including its other
+// pseudonyms
+fn notify_person(peer_map: &PeerMap, sub: &str, response: ChatResponse) {
+    let peers = peer_map.lock().unwrap();
+    let frame = response.to_shared();
+    for user in same_person(&peers, sub) {
+        for connection in peers[&user].connections.values() {
+            connection.tx.send(frame.clone());
+        }
+    }
+}
+
+// send a chat message to all connections that joined its room, the message is encoded once
+// for the devices of the author and once for all others
+fn broadcast(directory: &RoomDirectory, message: &StoredMessage, parent: Option<&StoredMessage>) {
+    let encode = |its_me: bool| {
+        ChatResponse::Message(ChatMessage::from_stored(message, its_me, parent)).to_shared()
+    };
+    let (mine, others) = (encode(true), encode(false));
+
+    for member in directory.members(&message.room).iter() {
+        let frame = if member.user == message.user {
+            &mine
+        } else {
+            &others
+        };
+        member.tx.send(frame.clone());
+    }
+}
+
+// send a response to all connections that joined a room
+fn notify_room(directory: &RoomDirectory, room: &str, response: ChatResponse) {
+    directory.send(room, &response.to_shared(), |_| true);
+}
+
+// send a direct message to all connections of the recipient and echo it to the sender
+fn send_direct(state: &ChatState, jwt: &SessionJwt, to: &str, text: &str) -> Result<(), ChatError> {
+    let user = jwt.sub.as_str();
+    let recipients: Vec<(bool, ClientQueue)> = {
+        let peers = state.peers.lock().unwrap();
+
+        if !peers.contains_key(to) {
+            return Err(ChatError::new(
+                ErrorCode::UserOffline,
+                format!("User is not online: {}", to),
+            ));
+        }
+
+        // the devices of the recipient, and of the sender unless it sends to itself
+        let mut users = vec![to];
+        if to != user {
+            users.push(user);
+        }
+
+        users
+            .into_iter()
+            .filter_map(|name| peers.get(name).map(|client| (name == user, client)))
+            .flat_map(|(its_me, client)| {
+                client
+                    .connections
+                    .values()
+                    .map(move |connection| (its_me, connection.tx.clone()))
+            })
+            .collect()
+    };
+
+    let id = state.ids.next();
+    let encode = |its_me: bool| {
+        let chat_msg = ChatMessage {
+            id,
+            room: None,
+            to: Some(to.to_string()),
+            user: user.to_string(),
+            time: Utc::now().timestamp(),
+            its_me,
+            msg: text.to_string(),
+            edited: None,
+            reply_to: None,
+            quote: None,
+            reactions: Vec::new(),
+            badges: jwt.badges.clone(),
+            attachment: None,
+        };
+        ChatResponse::Message(chat_msg).to_shared()
+    };
+    let (mine, others) = (encode(true), encode(false));
+
+    for (its_me, tx) in recipients {
+        tx.send(if its_me { mine.clone() } else { others.clone() });
+    }
+
+    Ok(())
+}
+
+// a page of messages of a room for a user, with a cursor to retrieve the older messages
+fn history_page(
+    state: &ChatState,
+    user: &str,
+    room: &str,
+    messages: Vec<StoredMessage>,
+) -> HistoryPage {
+    // a full page means there might be older messages
+    let cursor = match messages.first() {
+        Some(message) if messages.len() == state.config.history_size => Some(message.id),
+        _ => None,
+    };
+
+    HistoryPage {
+        room: room.to_string(),
+        messages: chat_messages(state, &messages, user),
+        cursor,
+    }
+}
+
+// the users that can be mentioned in a room: users that are online or wrote a message, in gated
+// rooms only the members are known to meet the requirements
+fn mentionable(state: &ChatState, room: &str) -> Vec<String> {
+    let peers = state.peers.lock().unwrap();
+    if chat_room::is_gated(room) {
+        return room_users(&peers, room).into_iter().collect();
+    }
+
+    let mut users: BTreeSet<String> = peers.keys().cloned().collect();
+    drop(peers);
+    users.extend(state.authors.lock().unwrap().iter().cloned());
+
+    users.into_iter().collect()
+}
+
+// notify the users mentioned in a message in any room they are in, mentions are kept until they
+// are acknowledged, the author is warned about names that could refer to multiple users
+fn notify_mentions(state: &ChatState, addr: &SocketAddr, message: &StoredMessage) {
+    if !message.msg.contains('@') {
+        return;
+    }
+
+    let mentions = mentions::resolve(&message.msg, &mentionable(state, &message.room));
+    for (name, candidates) in mentions.ambiguous {
+        let warning = ChatError::ambiguous_mention(&name, candidates);
+        reply(
+            &state.peers,
+            &message.user,
+            addr,
+            ChatResponse::Warning(warning),
+        );
+    }
+
+    let notification = ChatResponse::Mention(ChatMessage::from_stored(message, false, None));
+    for user in mentions
+        .subjects
+        .iter()
+        .filter(|user| **user != message.user)
+    {
+        if let Err(e) = state
+            .store
+            .add_mention(pseudonym::stable_id(user), message.id)
+        {
+            error!("Could not store mention of {}: {:?}", user, e);
+        }
+        notify_user(&state.peers, user, notification.clone());
+    }
+}
+
+// deliver the mentions a user did not acknowledge yet to a new connection
+fn send_unread_mentions(state: &ChatState, addr: &SocketAddr, user: &str) {
+    let mentions = match state.store.unread_mentions(pseudonym::stable_id(user)) {
+        Ok(mentions) => mentions,
+        Err(e) => {
+            error!("Could not retrieve the mentions of {}: {:?}", user, e);
+            return;
+        }
+    };
+
+    for message in mentions.iter() {
+        let notification = ChatResponse::Mention(ChatMessage::from_stored(message, false, None));
+        reply(&state.peers, user, addr, notification);
+    }
+}
+
+// send a page of the history of a room to a single connection
+fn send_history(
+    state: &ChatState,
+    addr: &SocketAddr,
+    user: &str,
+    room: &str,
+    before: Option<i64>,
+) -> Result<(), ChatError> {
+    let messages = state
+        .store
+        .history(room, before, state.config.history_size)
+        .map_err(|e| storage_error(&format!("retrieve the history of {}", room), e))?;
+
+    let page = history_page(state, user, room, messages);
+    reply(&state.peers, user, addr, ChatResponse::History(page));
+
+    Ok(())
+}
+
+// send a page of the messages of a room matching a query to a single connection
+fn send_search_results(
+    state: &ChatState,
+    addr: &SocketAddr,
+    user: &str,
+    room: &str,
+    query: &SearchQuery,
+    before: Option<i64>,
+) -> Result<(), ChatError> {
+    let limit = state.config.history_size;
+    let messages = state
+        .store
+        .search(room, query, before, limit)
+        .map_err(|e| storage_error(&format!("search the history of {}", room), e))?;
+
+    let page = history_page(state, user, room, messages);
+    reply(&state.peers, user, addr, ChatResponse::Search(page));
+
+    Ok(())
+}
+
+// make sure a connection joined a room before it interacts with it
+fn check_member(
+    peer_map: &PeerMap,
+    user: &str,
+    addr: &SocketAddr,
+    room: &str,
+) -> Result<(), ChatError> {
+    let is_member = match connection(&peer_map.lock().unwrap(), user, addr) {
+        Some(connection) => connection.rooms.contains(room),
+        None => false,
+    };
+
+    if !is_member {
+        return Err(ChatError::new(
+            ErrorCode::NotAMember,
+            format!("Not a member of room: {}", room),
+        ));
+    }
+
+    Ok(())
+}
+
+// validate the text of a message and remove invisible characters before it is sent or stored
+fn check_content(state: &ChatState, text: &str) -> Result<String, ChatError> {
+    Ok(content::sanitize(text, state.config.max_message_length)?)
+}
+
+// make sure a user is allowed to post, i.e. after flooding a room
+fn check_muted(state: &ChatState, user: &str) -> Result<(), ChatError> {
+    let now = Instant::now();
+
+    match state.limiter.lock().unwrap().muted_until(user, now) {
+        Some(until) => Err(ChatError::new(
+            ErrorCode::Muted,
+            format!("Muted for {} seconds", (until - now).as_secs() + 1),
+        )),
+        None => Ok(()),
+    }
+}
+
+// spend a token for a request of a client, returns whether the request should be handled
+fn rate_limit(state: &ChatState, addr: &SocketAddr, user: &str) -> bool {
+    let limit =
+        state
+            .limiter
+            .lock()
+            .unwrap()
+            .check(addr, user, &state.config.rate_limits, Instant::now());
+
+    match limit {
+        RateLimit::Allowed => return true,
+        RateLimit::Warned => {
+            warn!("{} exceeded the rate limit", user);
+            let warning = ChatError::new(
+                ErrorCode::RateLimited,
+                "Too many requests, further requests will be dropped".to_string(),
+            );
+            reply(&state.peers, user, addr, ChatResponse::Warning(warning));
+        }
+        RateLimit::Dropped => {}
+        RateLimit::Muted(_) => {
+            warn!("{} is muted for flooding", user);
+            if let Err(error) = check_muted(state, user) {
+                reply(&state.peers, user, addr, ChatResponse::Error(error));
+            }
+        }
+    }
+
+    false
+}
+
+// look up a message in the history
+fn find_message(state: &ChatState, id: i64) -> Result<StoredMessage, ChatError> {
+    let not_found = || ChatError::new(ErrorCode::NotFound, format!("Message not found: {}", id));
+
+    match state.store.get(id) {
+        Ok(Some(message)) => Ok(message),
+        Ok(None) => Err(not_found()),
+        Err(e) => Err(storage_error(&format!("retrieve message {}", id), e)),
+    }
+}
+
+// look up the message a message replies to, if it still exists
+fn find_parent(state: &ChatState, message: &StoredMessage) -> Option<StoredMessage> {
+    message.reply_to.and_then(|id| find_message(state, id).ok())
+}
+
+// convert messages from the history to chat messages for a user, quoting their parents
+fn chat_messages(state: &ChatState, messages: &[StoredMessage], user: &str) -> Vec<ChatMessage> {
+    messages
+        .iter()
+        .map(|message| {
+            let parent = find_parent(state, message);
+            ChatMessage::from_stored(message, message.user == user, parent.as_ref())
+        })
+        .collect()
+}
+
+// look up a message in the history that the user is still allowed to edit or delete
+fn own_message(state: &ChatState, id: i64, user: &str) -> Result<StoredMessage, ChatError> {
+    let message = find_message(state, id)?;
+
+    if message.user != user {
+        return Err(ChatError::new(
+            ErrorCode::Forbidden,
+            format!("Not the author of message: {}", id),
+        ));
+    }
+
+    if Utc::now().timestamp() - message.time > state.config.edit_window.as_secs() as i64 {
+        return Err(ChatError::new(
+            ErrorCode::Forbidden,
+            format!("Message can no longer be changed: {}", id),
+        ));
+    }
+
+    Ok(message)
+}
+
+// add a new message to the history, its author can be mentioned from then on
+fn store_message(state: &ChatState, message: &StoredMessage) -> Result<(), ChatError> {
+    state
+        .store
+        .insert(message)
+        .map_err(|e| storage_error("store the message", e))?;
+
+    let mut authors = state.authors.lock().unwrap();
+    if !authors.contains(&message.user) {
+        authors.insert(message.user.clone());
+    }
+
+    Ok(())
+}
+
+// remove a message from the history and tell the members of its room
+fn remove_message(state: &ChatState, message: &StoredMessage) -> Result<(), ChatError> {
+    state
+        .store
+        .delete(message.id)
+        .map_err(|e| storage_error(&format!("delete message {}", message.id), e))?;
+
+    let response = ChatResponse::Delete {
+        room: message.room.clone(),
+        id: message.id,
+    };
+    notify_room(&state.directory, &message.room, response);
+
+    Ok(())
+}
+
+// an attachment that does not meet the limits
+fn invalid_attachment(message: String) -> ChatError {
+    ChatError::new(ErrorCode::InvalidAttachment, message)
+}
+
+// a failure of the message or attachment store, the details are only logged
+fn storage_error(action: &str, e: Error) -> ChatError {
+    error!("Could not {}: {:?}", action, e);
+    ChatError::new(ErrorCode::Storage, format!("Could not {}", action))
+}
+
+// whether a connection announced an attachment of which it is sending the content
+fn is_uploading(state: &ChatState, addr: &SocketAddr, user: &str) -> bool {
+    state
+        .peers
+        .lock()
+        .unwrap()
+        .get(user)
+        .and_then(|client| client.connections.get(addr))
+        .is_some_and(|connection| connection.upload.is_some())
+}
+
+// add a binary frame to the attachment a connection is uploading, the attachment is stored and
+// sent to its room when it is complete
+fn receive_chunk(
+    state: &ChatState,
+    addr: &SocketAddr,
+    jwt: &SessionJwt,
+    chunk: &[u8],
+) -> Result<(), ChatError> {
+    let upload = {
+        let mut peers = state.peers.lock().unwrap();
+        let connection = match peers
+            .get_mut(&jwt.sub)
+            .and_then(|client| client.connections.get_mut(addr))
+        {
+            Some(connection) => connection,
+            None => return Ok(()),
+        };
+
+        let complete = match connection.upload.as_mut() {
+            Some(upload) => upload.append(chunk),
+            None => {
+                return Err(ChatError::new(
+                    ErrorCode::InvalidRequest,
+                    "Unexpected binary frame, no attachment was announced".to_string(),
+                ))
+            }
+        };
+
+        match complete {
+            Ok(false) => return Ok(()),
+            Ok(true) => connection.upload.take(),
+            Err(message) => {
+                connection.upload = None;
+                return Err(invalid_attachment(message));
+            }
+        }
+    };
+
+    let upload = match upload {
+        Some(upload) => upload,
+        None => return Ok(()),
+    };
+    let (room, text) = (upload.room.clone(), upload.text.clone());
+    check_member(&state.peers, &jwt.sub, addr, &room)?;
+
+    let (attachment, data) = upload.finish().map_err(invalid_attachment)?;
+    state
+        .attachments
+        .put(&attachment.hash, &data)
+        .map_err(|e| storage_error(&format!("store attachment {}", &attachment.hash), e))?;
+
+    info!(
+        "Received attachment {} from {} in {}",
+        &attachment.hash, addr, room
+    );
+    let message = StoredMessage {
+        id: state.ids.next(),
+        room,
+        user: jwt.sub.clone(),
+        time: Utc::now().timestamp(),
+        msg: text,
+        edited: None,
+        reply_to: None,
+        reactions: Vec::new(),
+        badges: jwt.badges.clone(),
+        attachment: Some(attachment),
+    };
+    store_message(state, &message)?;
+    broadcast(&state.directory, &message, None);
+
+    Ok(())
+}
+
+// send the content of an attachment to a single connection, a download response is followed by
+// the content in binary frames
+fn send_attachment(
+    peer_map: &PeerMap,
+    user: &str,
+    addr: &SocketAddr,
+    id: i64,
+    attachment: Attachment,
+    data: Vec<u8>,
+) {
+    let chunks: Vec<&[u8]> = data.chunks(attachments::CHUNK_SIZE).collect();
+    let response = ChatResponse::Download {
+        id,
+        attachment,
+        chunks: chunks.len(),
+    };
+
+    if let Some(connection) = connection(&peer_map.lock().unwrap(), user, addr) {
+        connection.tx.send(response.to_message());
+        for chunk in chunks {
+            connection.tx.send(Message::Binary(chunk.to_vec()));
+        }
+    }
+}
+
+// add or remove the reaction of a user to a message in a joined room
+fn react(
+    state: &ChatState,
+    addr: &SocketAddr,
+    user: &str,
+    id: i64,
+    emoji: &str,
+    add: bool,
+) -> Result<(), ChatError> {
+    if !message_store::is_valid_emoji(emoji) {
+        return Err(ChatError::new(
+            ErrorCode::InvalidRequest,
+            format!("Invalid reaction: {}", emoji),
+        ));
+    }
+
+    let room = find_message(state, id)?.room;
+    check_member(&state.peers, user, addr, &room)?;
+
+    let message = match state.store.react(id, emoji, user, add) {
+        Ok(Some(message)) => message,
+        Ok(None) => {
+            return Err(ChatError::new(
+                ErrorCode::NotFound,
+                format!("Message not found: {}", id),
+            ))
+        }
+        Err(e) => return Err(storage_error(&format!("store reaction to {}", id), e)),
+    };
+
+    let response = ChatResponse::Reactions {
+        room,
+        id,
+        reactions: message.reactions,
+    };
+    notify_room(&state.directory, &message.room, response);
+
+    Ok(())
+}
+
+// add a connection to a room, announce it to the other members and send the users in the room
+// and recent messages
+fn join_room(
+    state: &ChatState,
+    addr: &SocketAddr,
+    room: &str,
+    jwt: &SessionJwt,
+) -> Result<(), ChatError> {
+    if !chat_room::is_valid_name(room) {
+        return Err(ChatError::new(
+            ErrorCode::InvalidRoom,
+            format!("Invalid room name: {}", room),
+        ));
+    }
+
+    // gated rooms can only be joined by users that disclosed the required attributes
+    let missing = chat_room::missing_requirements(room, &jwt.attributes);
+    if !missing.is_empty() {
+        return Err(ChatError::missing_attributes(room, missing));
+    }
+
+    let user = &jwt.sub;
+
+    let users = {
+        let mut peers = state.peers.lock().unwrap();
+        let client = match peers.get_mut(user) {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+        let was_present = client.in_room(room);
+
+        let connection = match client.connections.get_mut(addr) {
+            Some(connection) => connection,
+            None => return Ok(()),
+        };
+
+        if !connection.rooms.insert(room.to_string()) {
+            return Ok(());
+        }
+
+        let member = Member {
+            addr: *addr,
+            user: user.to_string(),
+            tx: connection.tx.clone(),
+        };
+        state.directory.join(room, member);
+
+        if !was_present {
+            announce_presence(&state.directory, room, user, PresenceAction::Join);
+        }
+
+        room_users(&peers, room)
+    };
+
+    let online = ChatResponse::Online {
+        room: room.to_string(),
+        users: users.into_iter().collect(),
+    };
+    reply(&state.peers, user, addr, online);
+
+    let latest = state
+        .store
+        .history(room, None, 1)
+        .map_err(|e| storage_error(&format!("retrieve the history of {}", room), e))?;
+    if latest.is_empty() {
+        return Ok(());
+    }
+
+    send_history(state, addr, user, room, None)
+}
+
+// list the default room and all other rooms that have members, counting users once
+fn list_rooms(peer_map: &PeerMap, user: &str, addr: &SocketAddr) -> Vec<RoomInfo> {
+    let mut rooms: BTreeMap<String, RoomInfo> = BTreeMap::new();
+    let default_room = chat_room::default_room();
+
+    rooms.insert(
+        default_room.clone(),
+        RoomInfo {
+            name: default_room,
+            members: 0,
+            joined: false,
+        },
+    );
+
+    let peers = peer_map.lock().unwrap();
+    for client in peers.values() {
+        let joined: BTreeSet<&String> = client
+            .connections
+            .values()
+            .flat_map(|connection| connection.rooms.iter())
+            .collect();
+
+        for name in joined {
+            let room = rooms.entry(name.clone()).or_insert_with(|| RoomInfo {
+                name: name.clone(),
+                members: 0,
+                joined: false,
+            });
+
+            room.members += 1;
+        }
+    }
+
+    if let Some(connection) = connection(&peers, user, addr) {
+        for name in connection.rooms.iter() {
+            if let Some(room) = rooms.get_mut(name) {
+                room.joined = true;
+            }
+        }
+    }
+
+    rooms.into_values().collect()
+}
+
+// handle a typed request of an authenticated client
+fn handle_request(
+    state: &ChatState,
+    addr: &SocketAddr,
+    jwt: &SessionJwt,
+    request: ChatRequest,
+) -> Result<(), ChatError> {
+    let peer_map = &state.peers;
+
+    match request {
+        ChatRequest::Auth { .. } => Err(ChatError::new(
+            ErrorCode::InvalidRequest,
+            "Already authenticated".to_string(),
+        )),
+        ChatRequest::Send {
+            room,
+            text,
+            reply_to,
+        } => {
+            let room = room.unwrap_or_else(chat_room::default_room);
+            check_member(peer_map, &jwt.sub, addr, &room)?;
+            check_muted(state, &jwt.sub)?;
+            let text = check_content(state, &text)?;
+
+            // replies should refer to a message in the same room
+            let parent = match reply_to {
+                Some(id) => Some(find_message(state, id)?),
+                None => None,
+            };
+            if let Some(parent) = &parent {
+                if parent.room != room {
+                    return Err(ChatError::new(
+                        ErrorCode::InvalidRequest,
+                        format!("Message {} is not in room: {}", parent.id, room),
+                    ));
+                }
+            }
+
+            info!("Received a message from {} in {}: {}", addr, room, text);
+            let message = StoredMessage {
+                id: state.ids.next(),
+                room,
+                user: jwt.sub.clone(),
+                time: Utc::now().timestamp(),
+                msg: text,
+                edited: None,
+                reply_to,
+                reactions: Vec::new(),
+                badges: jwt.badges.clone(),
+                attachment: None,
+            };
+            store_message(state, &message)?;
+            broadcast(&state.directory, &message, parent.as_ref());
+            notify_mentions(state, addr, &message);
+
+            Ok(())
+        }
+        ChatRequest::Upload {
+            room,
+            name,
+            mime,
+            size,
+            text,
+        } => {
+            let room = room.unwrap_or_else(chat_room::default_room);
+            check_member(peer_map, &jwt.sub, addr, &room)?;
+            check_muted(state, &jwt.sub)?;
+
+            // the text of an attachment is optional
+            let max_length = state.config.max_message_length;
+            let text = match content::sanitize(&text.unwrap_or_default(), max_length) {
+                Ok(text) => text,
+                Err(Rejection::Empty) => String::new(),
+                Err(rejection) => return Err(rejection.into()),
+            };
+
+            let limits = &state.config.attachments;
+            let upload =
+                Upload::new(room, text, name, mime, size, limits).map_err(invalid_attachment)?;
+
+            // an unfinished upload of the connection is replaced
+            info!("{} announced an attachment of {} bytes", addr, size);
+            let mut peers = peer_map.lock().unwrap();
+            if let Some(connection) = peers
+                .get_mut(&jwt.sub)
+                .and_then(|client| client.connections.get_mut(addr))
+            {
+                connection.upload = Some(upload);
+            }
+
+            Ok(())
+        }
+        ChatRequest::Download { id } => {
+            let message = find_message(state, id)?;
+            check_member(peer_map, &jwt.sub, addr, &message.room)?;
+
+            let not_found =
+                || ChatError::new(ErrorCode::NotFound, format!("Attachment not found: {}", id));
+            let attachment = message.attachment.ok_or_else(not_found)?;
+            let data = match state.attachments.get(&attachment.hash) {
+                Ok(Some(data)) => data,
+                Ok(None) => return Err(not_found()),
+                Err(e) => {
+                    let action = format!("retrieve attachment {}", &attachment.hash);
+                    return Err(storage_error(&action, e));
+                }
+            };
+
+            send_attachment(peer_map, &jwt.sub, addr, id, attachment, data);
+
+            Ok(())
+        }
+        ChatRequest::Direct { to, text } => {
+            check_muted(state, &jwt.sub)?;
+            let text = check_content(state, &text)?;
+            send_direct(state, jwt, &to, &text)
+        }
+        ChatRequest::Edit { id, text } => {
+            check_muted(state, &jwt.sub)?;
+            let text = check_content(state, &text)?;
+            let message = own_message(state, id, &jwt.sub)?;
+            let edited = Utc::now().timestamp();
+
+            info!("{} edited message {}", &jwt.sub, id);
+            state
+                .store
+                .edit(id, &text, edited)
+                .map_err(|e| storage_error(&format!("edit message {}", id), e))?;
+
+            let response = ChatResponse::Edit {
+                room: message.room.clone(),
+                id,
+                msg: text,
+                edited,
+            };
+            notify_room(&state.directory, &message.room, response);
+
+            Ok(())
+        }
+        ChatRequest::Delete { id } => {
+            let message = own_message(state, id, &jwt.sub)?;
+
+            info!("{} deleted message {}", &jwt.sub, id);
+            remove_message(state, &message)
+        }
+        ChatRequest::React { id, emoji, add } => {
+            check_muted(state, &jwt.sub)?;
+            react(state, addr, &jwt.sub, id, &emoji, add)
+        }
+        ChatRequest::Join { room } => join_room(state, addr, &room, jwt),
+        ChatRequest::Leave { room } => {
+            let mut peers = peer_map.lock().unwrap();
+            leave_room(&mut peers, &state.directory, &jwt.sub, addr, &room);
+
+            Ok(())
+        }
+        ChatRequest::Rooms => {
+            let rooms = list_rooms(peer_map, &jwt.sub, addr);
+            reply(peer_map, &jwt.sub, addr, ChatResponse::Rooms { rooms });
+
+            Ok(())
+        }
+        ChatRequest::History { room, before } => {
+            check_member(peer_map, &jwt.sub, addr, &room)?;
+            send_history(state, addr, &jwt.sub, &room, before)
+        }
+        ChatRequest::Search {
+            room,
+            query,
+            before,
+        } => {
+            check_member(peer_map, &jwt.sub, addr, &room)?;
+
+            // the history of a gated room is only searchable with the required attributes
+            let missing = chat_room::missing_requirements(&room, &jwt.attributes);
+            if !missing.is_empty() {
+                return Err(ChatError::missing_attributes(&room, missing));
+            }
+
+            send_search_results(state, addr, &jwt.sub, &room, &query, before)
+        }
+        ChatRequest::Thread { id } => {
+            let root = find_message(state, id)?;
+            check_member(peer_map, &jwt.sub, addr, &root.room)?;
+
+            let messages = state
+                .store
+                .thread(id)
+                .map_err(|e| storage_error(&format!("retrieve thread {}", id), e))?;
+            let thread = ThreadPage {
+                room: root.room,
+                id,
+                messages: chat_messages(state, &messages, &jwt.sub),
+            };
+            reply(peer_map, &jwt.sub, addr, ChatResponse::Thread(thread));
+
+            Ok(())
+        }
+        ChatRequest::Typing { room, typing } => {
+            check_member(peer_map, &jwt.sub, addr, &room)?;
+            check_muted(state, &jwt.sub)?;
+
+            let update = state.typing.lock().unwrap().update(
+                &room,
+                &jwt.sub,
+                typing,
+                state.config.typing_throttle,
+            );
+
+            match update {
+                TypingUpdate::Started => {
+                    relay_typing(&state.directory, &room, &jwt.sub, true);
+                    tokio::spawn(expire_typing(state.clone(), room, jwt.sub.clone()));
+                }
+                TypingUpdate::Stopped => relay_typing(&state.directory, &room, &jwt.sub, false),
+                TypingUpdate::Ignored => {}
+            }
+
+            Ok(())
+        }
+        ChatRequest::Ack { id, ids } => {
+            // acknowledged mentions are no longer delivered
+            for id in id.into_iter().chain(ids) {
+                debug!("{} acknowledged {}", &jwt.sub, id);
+
+                state
+                    .store
+                    .read_mention(pseudonym::stable_id(&jwt.sub), id)
+                    .map_err(|e| storage_error(&format!("mark mention {} as read", id), e))?;
+            }
+
+            Ok(())
+        }
+        ChatRequest::Ping => {
+            reply(peer_map, &jwt.sub, addr, ChatResponse::Pong);
+
+            Ok(())
+        }
+        ChatRequest::Logout => {
+            info!("{} logged out", &jwt.sub);
+            state.revocations.lock().unwrap().revoke_token(jwt);
+            disconnect_subject(state, &jwt.sub, "logout");
+
+            Ok(())
+        }
+        ChatRequest::Kick { subject } => {
+            check_role(jwt, Role::Moderator, "kick users")?;
+
+            warn!("{} kicked {}", &jwt.sub, &subject);
+            disconnect_person(state, &subject, "kicked");
+
+            Ok(())
+        }
+        ChatRequest::Mute { subject, seconds } => {
+            check_role(jwt, Role::Moderator, "mute users")?;
+
+            let until = Instant::now()
+                .checked_add(Duration::from_secs(seconds))
+                .ok_or_else(|| {
+                    ChatError::new(
+                        ErrorCode::InvalidRequest,
+                        format!("Invalid mute duration: {}", seconds),
+                    )
+                })?;
+
+            warn!("{} muted {} for {} seconds", &jwt.sub, &subject, seconds);
+            state.limiter.lock().unwrap().mute(&subject, until);
+
+            let warning = ChatError::new(
+                ErrorCode::Muted,
+                format!("Muted by a moderator for {} seconds", seconds),
+            );
+            notify_person(peer_map, &subject, ChatResponse::Warning(warning));
+
+            Ok(())
+        }
+        ChatRequest::Ban { subject, seconds } => {
+            check_role(jwt, Role::Moderator, "ban users")?;
+
+            // bans without a duration last until the server restarts
+            let until = match seconds.map(i64::try_from) {
+                Some(Ok(seconds)) => Utc::now().timestamp().saturating_add(seconds),
+                _ => i64::MAX,
+            };
+            warn!("{} banned {} until {}", &jwt.sub, &subject, until);
+            state
+                .revocations
+                .lock()
+                .unwrap()
+                .ban_subject(&subject, until);
+            disconnect_person(state, &subject, "banned");
+
+            Ok(())
+        }
+        ChatRequest::Remove { id } => {
+            check_role(jwt, Role::Moderator, "remove messages")?;
+            let message = find_message(state, id)?;
+
+            warn!("{} removed message {} of {}", &jwt.sub, id, &message.user);
+            remove_message(state, &message)
+        }
+        ChatRequest::Metrics => {
+            check_role(jwt, Role::Admin, "view metrics")?;
+
+            let stats = {
+                let peers = peer_map.lock().unwrap();
+                let connections = peers
+                    .values()
+                    .flat_map(|client| client.connections.values());
+                state
+                    .metrics
+                    .stats(connections.map(|connection| &connection.tx))
+            };
+            reply(peer_map, &jwt.sub, addr, ChatResponse::Metrics(stats));
+
+            Ok(())
+        }
+        ChatRequest::Revoke { subject } => {
+            check_role(jwt, Role::Admin, "revoke sessions")?;
+
+            warn!("{} revoked all sessions of {}", &jwt.sub, &subject);
+            state.revocations.lock().unwrap().revoke_subject(&subject);
+            disconnect_person(state, &subject, "logout");
+
+            Ok(())
+        }
+    }
 }
 
-#[derive(Serialize, Debug, Clone)]
-struct ChatMessage {
-    user: String,
-    time: i64,
-    its_me: bool,
-    msg: Option<String>,
+// run blocking work, like calls of the message store, on the threads reserved for blocking
+// work, so the other connections on the same worker thread are not stalled
+async fn blocking<T, F>(work: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(work).await {
+        Ok(result) => result,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
 }
 
-impl ChatMessage {
-    // encode a chat message to a ws message (as json)
-    fn to_message(&self) -> Message {
-        let encoded_chat_message = serde_json::to_string(self).unwrap();
+// every client starts in the default room, when it is allowed to, and receives the mentions it
+// missed
+fn start_session(state: &ChatState, addr: &SocketAddr, jwt: &SessionJwt) {
+    if let Err(error) = join_room(state, addr, &chat_room::default_room(), jwt) {
+        warn!(
+            "{} could not join the default room: {}",
+            addr, error.message
+        );
+        reply(&state.peers, &jwt.sub, addr, ChatResponse::Error(error));
+    }
+    send_unread_mentions(state, addr, &jwt.sub);
+}
+
+// handle a frame of an authenticated client
+fn handle_frame(state: &ChatState, addr: &SocketAddr, jwt: &SessionJwt, request: SocketRequest) {
+    // ignore messages of sessions that were revoked while their close is pending
+    if state.revocations.lock().unwrap().is_revoked(jwt) {
+        return;
+    }
+
+    // binary frames contain the content of an announced attachment, they are limited by the
+    // announced size instead of the rate limits, stray frames are limited like requests
+    if let Some(chunk) = request.binary() {
+        if !is_uploading(state, addr, &jwt.sub) && !rate_limit(state, addr, &jwt.sub) {
+            return;
+        }
+
+        if let Err(error) = receive_chunk(state, addr, jwt, chunk) {
+            warn!("Rejected attachment from {}: {}", addr, error.message);
+            reply(&state.peers, &jwt.sub, addr, ChatResponse::Error(error));
+        }
 
-        encoded_chat_message.into()
+        return;
+    }
+
+    // requests of flooding clients are dropped
+    if !rate_limit(state, addr, &jwt.sub) {
+        return;
+    }
+
+    let result = request
+        .chat_request()
+        .map_err(ChatError::from)
+        .and_then(|request| handle_request(state, addr, jwt, request));
+
+    if let Err(error) = result {
+        warn!("Rejected request from {}: {}", addr, error.message);
+        reply(&state.peers, &jwt.sub, addr, ChatResponse::Error(error));
     }
 }
 
-// in memory administration of connected clients
-pub type PeerMap = Arc<Mutex<HashMap<SocketAddr, ChatClient>>>;
+// ping a client at every heartbeat, resolves when it stopped answering
+async fn keep_alive(tx: &ClientQueue, liveness: &Liveness, heartbeat: Heartbeat) {
+    let mut pings = heartbeat.pings();
+
+    loop {
+        pings.tick().await;
+
+        if heartbeat.is_dead(liveness) {
+            return;
+        }
+        tx.send(Message::Ping(Vec::new()));
+    }
+}
+
+// verify the session JWT sent in the first request of a client
+fn authenticate(state: &ChatState, request: &SocketRequest) -> Result<SessionJwt, ChatError> {
+    let token = match request.chat_request()? {
+        ChatRequest::Auth { token } => token,
+        _ => {
+            return Err(ChatError::new(
+                ErrorCode::Authentication,
+                "Authentication error: expected an auth request".to_string(),
+            ))
+        }
+    };
+
+    SessionJwt::from_jwt(token)
+        .and_then(|jwt| state.revocations.lock().unwrap().check(jwt))
+        .map_err(|error| {
+            ChatError::new(
+                ErrorCode::Authentication,
+                format!("Authentication error: {:?}", error),
+            )
+        })
+}
 
 // handle a chat session
 async fn accept_chat_connection(
-    peer_map: PeerMap,
+    state: ChatState,
     stream: TcpStream,
     addr: SocketAddr,
+    mut shutdown: ShutdownSignal,
 ) -> Result<(), Error> {
     info!("Incoming TCP connection from: {}", addr);
-    let ws_stream = tokio_tungstenite::accept_async(stream).await?;
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(MAX_FRAME_SIZE),
+        max_frame_size: Some(MAX_FRAME_SIZE),
+        ..WebSocketConfig::default()
+    };
+    let ws_stream = tokio_tungstenite::accept_async_with_config(stream, Some(ws_config)).await?;
     let (mut write, mut read) = ws_stream.split();
 
     info!("WS connection established: {}", addr);
-    let token = SocketRequest::from_message(read.next().await)?;
+    // clients that do not authenticate are disconnected
+    let msg = tokio::select! {
+        msg = tokio::time::timeout(state.config.auth_timeout, read.next()) => match msg {
+            Ok(msg) => msg,
+            Err(_) => {
+                warn!("{} did not authenticate in time", addr);
+                write.send(Message::Close(None)).await?;
 
-    info!("Received message token: {:?}", &token);
-    let key = config::get("APP_JWT_KEY");
-    let jwt = match decode::<SessionJwt>(key, token.to_string()) {
+                return Ok(());
+            }
+        },
+        _ = shutdown.recv() => {
+            write.send(shutdown::going_away()).await?;
+
+            return Ok(());
+        }
+    };
+    let request = SocketRequest::from_message(msg)?;
+
+    let jwt = match authenticate(&state, &request) {
         Ok(jwt) => {
             info!("Found valid JWT token");
             jwt
         }
         Err(error) => {
-            let msg = json!({ "error": format!("Authentication error: {:?}", error) }).to_string();
-            write.send(msg.into()).await?;
+            write.send(ChatResponse::Error(error).to_message()).await?;
 
             return Err(Error::InvalidJWT);
         }
     };
 
     // add the new client to the peer administration
-    let (tx, rx) = unbounded();
-    peer_map.lock().unwrap().insert(
-        addr,
-        ChatClient {
-            user: jwt.sub.clone(),
-            tx,
-        },
+    let peer_map = state.peers.clone();
+    let (tx, rx) = client_queue::channel(
+        state.config.queue_size,
+        state.config.queue_policy,
+        state.metrics.clone(),
     );
+    let devices = {
+        let mut peers = peer_map.lock().unwrap();
+        // all pseudonyms of a person share the devices
+        let id = pseudonym::stable_id(&jwt.sub);
+        let devices: usize = peers
+            .iter()
+            .filter(|(sub, _)| pseudonym::stable_id(sub) == id)
+            .map(|(_, client)| client.connections.len())
+            .sum();
 
-    // broadcast that a new client connected
-    for (peer_addr, recipient) in peer_map.lock().unwrap().iter() {
-        let chat_msg = ChatMessage {
-            user: jwt.sub.clone(),
-            time: Utc::now().timestamp(),
-            its_me: peer_addr == &addr,
-            msg: None,
-        };
-        recipient.tx.unbounded_send(chat_msg.to_message()).unwrap();
-    }
+        if devices < state.config.max_devices {
+            let connection = Connection {
+                rooms: HashSet::new(),
+                tx: tx.clone(),
+                upload: None,
+            };
+            let client = peers.entry(jwt.sub.clone()).or_default();
+            client.connections.insert(addr, connection);
+        }
 
-    // forward all messages to all connected peers
-    let broadcast_incoming = read.try_for_each(|msg| {
-        info!(
-            "Received a message from {}: {}",
-            addr,
-            msg.to_text().unwrap()
+        devices
+    };
+
+    // users can only be connected from a limited number of devices at once
+    if devices >= state.config.max_devices {
+        warn!("{} is already connected on {} devices", &jwt.sub, devices);
+        let error = ChatError::new(
+            ErrorCode::TooManyDevices,
+            format!("Already connected on {} devices", devices),
         );
-        for (peer_addr, recipient) in peer_map.lock().unwrap().iter() {
-            let chat_msg = ChatMessage {
-                user: jwt.sub.clone(),
-                time: Utc::now().timestamp(),
-                its_me: peer_addr == &addr,
-                msg: Some(msg.to_string()),
-            };
-            recipient.tx.unbounded_send(chat_msg.to_message()).unwrap();
+        write.send(ChatResponse::Error(error).to_message()).await?;
+        write.send(Message::Close(None)).await?;
+
+        return Ok(());
+    }
+
+    let jwt = Arc::new(jwt);
+    {
+        let (state, jwt) = (state.clone(), jwt.clone());
+        blocking(move || start_session(&state, &addr, &jwt)).await;
+    }
+
+    // handle all typed requests in order, invalid requests are answered with an error
+    let liveness = Liveness::default();
+    let handle_incoming = async {
+        while let Some(msg) = read.next().await {
+            let request = SocketRequest::from(msg?);
+            liveness.seen();
+
+            // ignore pongs, other frames are handled where the message store can block
+            if request.is_close() || request.is_heartbeat() {
+                continue;
+            }
+
+            let (state, jwt) = (state.clone(), jwt.clone());
+            blocking(move || handle_frame(&state, &addr, &jwt, request)).await;
         }
 
-        future::ok(())
-    });
+        Ok::<(), tungstenite::Error>(())
+    };
+
+    // message plumbing, forward all incoming messages until the client disconnects, does not
+    // keep up with its messages or stops answering pings
+    let receive_from_others = rx.into_stream().map(Ok).forward(write);
 
-    // message plumbing, forward all incoming messages
-    let receive_from_others = rx.map(Ok).forward(write);
-    pin_mut!(broadcast_incoming, receive_from_others);
-    future::select(broadcast_incoming, receive_from_others).await;
+    // on shutdown the client is asked to reconnect, the connection ends when it answers the close
+    let go_away = async {
+        shutdown.recv().await;
+        tx.send(shutdown::going_away());
+        future::pending::<()>().await
+    };
+
+    tokio::select! {
+        _ = handle_incoming => {},
+        _ = receive_from_others => {},
+        _ = tx.overflowed() => {
+            warn!("Disconnecting {}, it does not keep up with its messages", &addr);
+        },
+        _ = keep_alive(&tx, &liveness, state.config.heartbeat) => {
+            warn!("Disconnecting {}, it does not answer pings", &addr);
+        },
+        _ = go_away => {},
+    }
 
     // when a client diconnects, remove them from the administration
     info!("{} disconnected", &addr);
-    peer_map.lock().unwrap().remove(&addr);
+    remove_peer(
+        &mut peer_map.lock().unwrap(),
+        &state.directory,
+        &jwt.sub,
+        &addr,
+    );
+    state.limiter.lock().unwrap().remove_connection(
+        &addr,
+        &state.config.rate_limits,
+        Instant::now(),
+    );
     Ok(())
 }
 
 // handle a chat ws connection and print blocking errors
-pub async fn handle_chat_connection(peer_map: PeerMap, stream: TcpStream, addr: SocketAddr) {
-    if let Err(e) = accept_chat_connection(peer_map, stream, addr).await {
+pub async fn handle_chat_connection(
+    state: ChatState,
+    stream: TcpStream,
+    addr: SocketAddr,
+    shutdown: ShutdownSignal,
+) {
+    if let Err(e) = accept_chat_connection(state, stream, addr, shutdown).await {
         error!("Error {:?}", e);
     }
 }
@@ -0,0 +1,249 @@
+use futures_core::Stream;
+use futures_util::stream;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+// what to do when a client does not keep up with the messages sent to it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueuePolicy {
+    // drop the oldest queued message to make room for the new one
+    DropOldest,
+    // close the connection of the slow client
+    Disconnect,
+}
+
+impl FromStr for QueuePolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "drop_oldest" => Ok(QueuePolicy::DropOldest),
+            "disconnect" => Ok(QueuePolicy::Disconnect),
+            _ => Err(format!("Unknown queue policy: {}", policy)),
+        }
+    }
+}
+
+// a message waiting to be sent, responses that are sent to many clients share their encoding
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outbound {
+    Message(Message),
+    Shared(Arc<str>),
+}
+
+impl From<Message> for Outbound {
+    fn from(message: Message) -> Self {
+        Outbound::Message(message)
+    }
+}
+
+impl From<Arc<str>> for Outbound {
+    fn from(text: Arc<str>) -> Self {
+        Outbound::Shared(text)
+    }
+}
+
+impl From<Outbound> for Message {
+    // the websocket library needs its own copy of every frame
+    fn from(outbound: Outbound) -> Self {
+        match outbound {
+            Outbound::Message(message) => message,
+            Outbound::Shared(text) => Message::Text(text.to_string()),
+        }
+    }
+}
+
+// counters of the outbound queues of all clients
+#[derive(Debug, Default)]
+pub struct QueueMetrics {
+    queued: AtomicUsize,
+    dropped: AtomicUsize,
+    disconnected: AtomicUsize,
+}
+
+// a snapshot of the queue metrics, as reported to administrators
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct QueueStats {
+    pub connections: usize,
+    pub queued: usize,
+    pub max_depth: usize,
+    pub dropped: usize,
+    pub disconnected: usize,
+}
+
+impl QueueMetrics {
+    // combine the counters with the depths of the currently connected clients
+    pub fn stats<'a>(&self, queues: impl Iterator<Item = &'a ClientQueue>) -> QueueStats {
+        let depths: Vec<usize> = queues.map(ClientQueue::depth).collect();
+
+        QueueStats {
+            connections: depths.len(),
+            queued: self.queued.load(Ordering::Relaxed),
+            max_depth: depths.into_iter().max().unwrap_or(0),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            disconnected: self.disconnected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    messages: VecDeque<Outbound>,
+    closed: bool,
+    overflowed: bool,
+}
+
+#[derive(Debug)]
+struct Shared {
+    queue: Mutex<Queue>,
+    notify: Notify,
+    overflow: Notify,
+    capacity: usize,
+    policy: QueuePolicy,
+    metrics: Arc<QueueMetrics>,
+}
+
+// sending half of a bounded outbound queue of a client
+#[derive(Debug, Clone)]
+pub struct ClientQueue {
+    shared: Arc<Shared>,
+}
+
+// receiving half of a bounded outbound queue, the queue is closed when it is dropped
+#[derive(Debug)]
+pub struct QueueReceiver {
+    shared: Arc<Shared>,
+}
+
+// create a bounded outbound queue for a client
+pub fn channel(
+    capacity: usize,
+    policy: QueuePolicy,
+    metrics: Arc<QueueMetrics>,
+) -> (ClientQueue, QueueReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::default(),
+        notify: Notify::new(),
+        overflow: Notify::new(),
+        capacity,
+        policy,
+        metrics,
+    });
+
+    (
+        ClientQueue {
+            shared: shared.clone(),
+        },
+        QueueReceiver { shared },
+    )
+}
+
+impl ClientQueue {
+    // queue a message for the client, returns false when the client is gone or too slow
+    pub fn send(&self, message: impl Into<Outbound>) -> bool {
+        let shared = &self.shared;
+        let mut queue = shared.queue.lock().unwrap();
+
+        if queue.closed {
+            return false;
+        }
+
+        if queue.messages.len() >= shared.capacity {
+            match shared.policy {
+                QueuePolicy::DropOldest => {
+                    queue.messages.pop_front();
+                    shared.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                    shared.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                QueuePolicy::Disconnect => {
+                    let dropped = queue.messages.len();
+                    queue.messages.clear();
+                    queue.closed = true;
+                    queue.overflowed = true;
+                    shared.metrics.queued.fetch_sub(dropped, Ordering::Relaxed);
+                    shared
+                        .metrics
+                        .dropped
+                        .fetch_add(dropped + 1, Ordering::Relaxed);
+                    shared.metrics.disconnected.fetch_add(1, Ordering::Relaxed);
+                    shared.notify.notify_one();
+                    shared.overflow.notify_one();
+
+                    return false;
+                }
+            }
+        }
+
+        queue.messages.push_back(message.into());
+        shared.metrics.queued.fetch_add(1, Ordering::Relaxed);
+        shared.notify.notify_one();
+
+        true
+    }
+
+    // number of messages waiting to be sent to the client
+    pub fn depth(&self) -> usize {
+        self.shared.queue.lock().unwrap().messages.len()
+    }
+
+    // resolves when the client was too slow and should be disconnected, the connection might
+    // be stalled so it can not wait for the remaining messages to be sent
+    pub async fn overflowed(&self) {
+        loop {
+            if self.shared.queue.lock().unwrap().overflowed {
+                return;
+            }
+
+            self.shared.overflow.notified().await;
+        }
+    }
+}
+
+impl QueueReceiver {
+    // wait for the next message, returns none when the queue was closed and is empty
+    pub async fn recv(&self) -> Option<Message> {
+        loop {
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+
+                if let Some(message) = queue.messages.pop_front() {
+                    self.shared.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                    return Some(message.into());
+                }
+
+                if queue.closed {
+                    return None;
+                }
+            }
+
+            self.shared.notify.notified().await;
+        }
+    }
+
+    // all messages of the queue as a stream
+    pub fn into_stream(self) -> impl Stream<Item = Message> {
+        stream::unfold(self, |receiver| async {
+            receiver.recv().await.map(|message| (message, receiver))
+        })
+    }
+}
+
+impl Drop for QueueReceiver {
+    // discard the remaining messages, further messages are refused
+    fn drop(&mut self) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let remaining = queue.messages.len();
+
+        queue.messages.clear();
+        queue.closed = true;
+        self.shared
+            .metrics
+            .queued
+            .fetch_sub(remaining, Ordering::Relaxed);
+    }
+}
@@ -1,6 +1,107 @@
+use crate::attachments::AttachmentLimits;
+use crate::client_queue::QueuePolicy;
+use crate::heartbeat::Heartbeat;
+use crate::rate_limit::{BucketLimit, RateLimits};
 use std::env;
+use std::str::FromStr;
+use std::time::Duration;
 
 // retrieve a application configuration from the environment
 pub fn get(key: &'static str) -> String {
     env::var(key).unwrap_or_else(|_| panic!("Fatal: the enviroment variable {} is required.", key))
 }
+
+// retrieve an optional application configuration from the environment
+pub fn get_or(key: &'static str, default: &str) -> String {
+    env::var(key).unwrap_or_else(|_| default.to_string())
+}
+
+// retrieve an optional application configuration that should be parsed, i.e. a number
+pub fn get_parsed_or<T: FromStr>(key: &'static str, default: T) -> T {
+    match env::var(key) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("Fatal: the enviroment variable {} is invalid.", key)),
+        Err(_) => default,
+    }
+}
+
+// retrieve an optional number that can not be zero, i.e. an interval or a count
+pub fn get_nonzero_or<T: FromStr + Default + PartialEq>(key: &'static str, default: T) -> T {
+    let value = get_parsed_or(key, default);
+    if value == T::default() {
+        panic!("Fatal: the enviroment variable {} should not be 0.", key);
+    }
+
+    value
+}
+
+// tunable settings of the chat server
+#[derive(Debug, Clone)]
+pub struct ChatConfig {
+    // number of messages replayed when joining a room and returned per history page
+    pub history_size: usize,
+    // maximum number of characters of a single message
+    pub max_message_length: usize,
+    // minimal time between relayed typing indicators of a user in a room
+    pub typing_throttle: Duration,
+    // time after which a user that did not refresh its typing indicator stops typing
+    pub typing_timeout: Duration,
+    // time during which the author of a message can still edit or delete it
+    pub edit_window: Duration,
+    // flood control per connection and per user
+    pub rate_limits: RateLimits,
+    // maximum number of messages waiting to be sent to a single connection
+    pub queue_size: usize,
+    // what to do with connections that do not keep up with their messages
+    pub queue_policy: QueuePolicy,
+    // maximum number of simultaneous connections of a single user
+    pub max_devices: usize,
+    // time a new connection has to send its session token
+    pub auth_timeout: Duration,
+    // pinging of clients to detect connections that were silently dropped
+    pub heartbeat: Heartbeat,
+    // size and types of attachments clients can upload
+    pub attachments: AttachmentLimits,
+}
+
+impl ChatConfig {
+    // read the chat settings from the environment, using defaults for missing settings
+    pub fn from_env() -> Self {
+        ChatConfig {
+            history_size: get_parsed_or("CHAT_HISTORY_SIZE", 50),
+            max_message_length: get_parsed_or("CHAT_MAX_MESSAGE_LENGTH", 2000),
+            typing_throttle: Duration::from_secs(get_parsed_or("CHAT_TYPING_THROTTLE", 2)),
+            typing_timeout: Duration::from_secs(get_parsed_or("CHAT_TYPING_TIMEOUT", 10)),
+            edit_window: Duration::from_secs(get_parsed_or("CHAT_EDIT_WINDOW", 300)),
+            rate_limits: RateLimits {
+                connection: BucketLimit {
+                    burst: get_parsed_or("CHAT_RATE_BURST", 10),
+                    refill: get_parsed_or("CHAT_RATE_REFILL", 1.0),
+                },
+                subject: BucketLimit {
+                    burst: get_parsed_or("CHAT_USER_RATE_BURST", 20),
+                    refill: get_parsed_or("CHAT_USER_RATE_REFILL", 2.0),
+                },
+                strikes: get_parsed_or("CHAT_MUTE_STRIKES", 5),
+                mute_duration: Duration::from_secs(get_parsed_or("CHAT_MUTE_DURATION", 60)),
+            },
+            queue_size: get_parsed_or("CHAT_QUEUE_SIZE", 256),
+            queue_policy: get_parsed_or("CHAT_QUEUE_POLICY", QueuePolicy::Disconnect),
+            max_devices: get_parsed_or("CHAT_MAX_DEVICES", 5),
+            auth_timeout: Duration::from_secs(get_parsed_or("CHAT_AUTH_TIMEOUT", 30)),
+            heartbeat: Heartbeat::from_env(),
+            attachments: AttachmentLimits {
+                max_size: get_parsed_or("CHAT_ATTACHMENT_SIZE", 5 * 1024 * 1024),
+                types: get_or(
+                    "CHAT_ATTACHMENT_TYPES",
+                    "image/png,image/jpeg,image/gif,image/webp,application/pdf",
+                )
+                .split(',')
+                .map(|mime| mime.trim().to_string())
+                .filter(|mime| !mime.is_empty())
+                .collect(),
+            },
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use serde::Serialize;
+use unicode_normalization::UnicodeNormalization;
+
+// why the text of a message was rejected, i.e. {"reason":"too_long","max_length":2000}
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum Rejection {
+    // nothing is left after removing whitespace and invisible characters
+    Empty,
+    // the text contains more characters than allowed
+    TooLong { max_length: usize },
+}
+
+// invisible formatting characters (general category Cf), i.e. zero width spaces, direction marks
+// and the bidi overrides that can make text appear in another order than it is stored
+pub fn is_format(c: char) -> bool {
+    matches!(c,
+        '\u{ad}'
+        | '\u{600}'..='\u{605}'
+        | '\u{61c}'
+        | '\u{6dd}'
+        | '\u{70f}'
+        | '\u{8e2}'
+        | '\u{180e}'
+        | '\u{200b}'..='\u{200f}'
+        | '\u{202a}'..='\u{202e}'
+        | '\u{2060}'..='\u{2064}'
+        | '\u{2066}'..='\u{206f}'
+        | '\u{feff}'
+        | '\u{fff9}'..='\u{fffb}'
+        | '\u{110bd}'
+        | '\u{110cd}'
+        | '\u{13430}'..='\u{13438}'
+        | '\u{1bca0}'..='\u{1bca3}'
+        | '\u{1d173}'..='\u{1d17a}'
+        | '\u{e0001}'
+        | '\u{e0020}'..='\u{e007f}'
+    )
+}
+
+// the zero width (non) joiner, which is needed in emoji sequences and some scripts
+fn is_joiner(c: char) -> bool {
+    c == '\u{200c}' || c == '\u{200d}'
+}
+
+// characters that show up in the text
+fn is_visible(c: char) -> bool {
+    !(c.is_whitespace() || c.is_control() || is_format(c))
+}
+
+// control characters are dropped, except for line breaks in multi line messages
+fn is_allowed(c: char) -> bool {
+    c == '\n' || !(c.is_control() || is_format(c))
+}
+
+// normalize the text of a message to NFC and remove control and formatting characters, joiners
+// are only kept between visible characters as in emoji sequences, surrounding whitespace is
+// trimmed and the length is counted in characters afterwards
+pub fn sanitize(text: &str, max_length: usize) -> Result<String, Rejection> {
+    let chars: Vec<char> = text.nfc().collect();
+    let mut sanitized = String::with_capacity(text.len());
+
+    for (position, c) in chars.iter().copied().enumerate() {
+        let joins = is_joiner(c)
+            && sanitized.chars().last().is_some_and(is_visible)
+            && chars.get(position + 1).copied().is_some_and(is_visible);
+
+        if joins || is_allowed(c) {
+            sanitized.push(c);
+        }
+    }
+    let sanitized = sanitized.trim();
+
+    if sanitized.is_empty() {
+        return Err(Rejection::Empty);
+    }
+
+    if sanitized.chars().count() > max_length {
+        return Err(Rejection::TooLong { max_length });
+    }
+
+    Ok(sanitized.to_string())
+}
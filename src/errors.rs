@@ -1,59 +1,97 @@
+use std::fmt;
 use tokio_tungstenite::tungstenite;
 
 // wrap errors from third party libraries in our own error type
 #[derive(Debug)]
 pub enum Error {
-    IgnorableError,
+    Ignorable,
     InvalidJWT,
+    RevokedJWT,
+    BannedSubject,
     InvalidJWTKey,
     InvalidProofStatus,
-    EnvironmentError(std::env::VarError),
-    ParseError(String),
-    SerializationError(serde_json::Error),
-    RequestError(reqwest::Error),
-    WebsocketError(tungstenite::Error),
-    JWTError(jsonwebtoken::errors::Error),
-    IoError(std::io::Error),
+    IdentityMismatch,
+    UnsupportedVersion(u32),
+    Environment(std::env::VarError),
+    Parse(String),
+    Serialization(serde_json::Error),
+    Request(reqwest::Error),
+    // boxed, the websocket error is much larger than the others
+    Websocket(Box<tungstenite::Error>),
+    JsonWebToken(jsonwebtoken::errors::Error),
+    Io(std::io::Error),
+    Database(rusqlite::Error),
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Ignorable => write!(f, "Ignorable message"),
+            Self::InvalidJWT => write!(f, "Invalid JWT"),
+            Self::RevokedJWT => write!(f, "Revoked JWT"),
+            Self::BannedSubject => write!(f, "Banned subject"),
+            Self::InvalidJWTKey => write!(f, "Invalid JWT key"),
+            Self::InvalidProofStatus => write!(f, "Invalid proof status"),
+            Self::IdentityMismatch => write!(f, "Disclosed identity does not match"),
+            Self::UnsupportedVersion(v) => write!(f, "Unsupported protocol version {}", v),
+            Self::Environment(e) => write!(f, "Environment error: {}", e),
+            Self::Parse(e) => write!(f, "Parse error: {}", e),
+            Self::Serialization(e) => write!(f, "Serialization error: {}", e),
+            Self::Request(e) => write!(f, "Request error: {}", e),
+            Self::Websocket(e) => write!(f, "Websocket error: {}", e),
+            Self::JsonWebToken(e) => write!(f, "JWT error: {}", e),
+            Self::Io(e) => write!(f, "IO error: {}", e),
+            Self::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 impl From<String> for Error {
     fn from(err: String) -> Self {
-        Self::ParseError(err)
+        Self::Parse(err)
     }
 }
 
 impl From<std::env::VarError> for Error {
     fn from(err: std::env::VarError) -> Self {
-        Self::EnvironmentError(err)
+        Self::Environment(err)
     }
 }
 
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
-        Self::IoError(err)
+        Self::Io(err)
     }
 }
 
 impl From<serde_json::Error> for Error {
     fn from(err: serde_json::Error) -> Self {
-        Self::SerializationError(err)
+        Self::Serialization(err)
     }
 }
 
 impl From<reqwest::Error> for Error {
     fn from(err: reqwest::Error) -> Self {
-        Self::RequestError(err)
+        Self::Request(err)
     }
 }
 
 impl From<tungstenite::Error> for Error {
     fn from(err: tungstenite::Error) -> Self {
-        Self::WebsocketError(err)
+        Self::Websocket(Box::new(err))
     }
 }
 
 impl From<jsonwebtoken::errors::Error> for Error {
     fn from(err: jsonwebtoken::errors::Error) -> Self {
-        Self::JWTError(err)
+        Self::JsonWebToken(err)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Self::Database(err)
     }
 }
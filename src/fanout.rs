@@ -0,0 +1,71 @@
+use crate::client_queue::ClientQueue;
+use arc_swap::ArcSwap;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+// a connection that receives the messages sent to a room
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub addr: SocketAddr,
+    pub user: String,
+    pub tx: ClientQueue,
+}
+
+type Rooms = HashMap<String, Arc<Vec<Member>>>;
+
+// copy-on-write index of the members of every room. Joining and leaving replaces the index,
+// messages are fanned out to a snapshot of the members without taking any lock
+#[derive(Debug, Default)]
+pub struct RoomDirectory {
+    rooms: ArcSwap<Rooms>,
+}
+
+impl RoomDirectory {
+    // the connections in a room at this moment
+    pub fn members(&self, room: &str) -> Arc<Vec<Member>> {
+        self.rooms.load().get(room).cloned().unwrap_or_default()
+    }
+
+    // add a connection to the members of a room
+    pub fn join(&self, room: &str, member: Member) {
+        self.rooms.rcu(|rooms| {
+            let mut rooms = Rooms::clone(rooms);
+            let members = rooms.entry(room.to_string()).or_default();
+            let mut updated = Vec::clone(members);
+            updated.push(member.clone());
+            *members = Arc::new(updated);
+
+            rooms
+        });
+    }
+
+    // remove a connection from the members of a room, forgetting rooms without members
+    pub fn leave(&self, room: &str, addr: &SocketAddr) {
+        self.rooms.rcu(|rooms| {
+            let mut rooms = Rooms::clone(rooms);
+            if let Some(members) = rooms.get_mut(room) {
+                let updated: Vec<Member> = members
+                    .iter()
+                    .filter(|member| member.addr != *addr)
+                    .cloned()
+                    .collect();
+
+                if updated.is_empty() {
+                    rooms.remove(room);
+                } else {
+                    *members = Arc::new(updated);
+                }
+            }
+
+            rooms
+        });
+    }
+
+    // queue an encoded response for the members of a room that match the filter
+    pub fn send(&self, room: &str, frame: &Arc<str>, filter: impl Fn(&Member) -> bool) {
+        for member in self.members(room).iter().filter(|member| filter(member)) {
+            member.tx.send(frame.clone());
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use crate::config;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// how often clients are pinged and how many pongs they can miss before they are disconnected
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub missed_pongs: u32,
+}
+
+impl Heartbeat {
+    // read the heartbeat settings of both websockets from the environment
+    pub fn from_env() -> Self {
+        Heartbeat {
+            interval: Duration::from_secs(config::get_nonzero_or("WS_PING_INTERVAL", 30)),
+            missed_pongs: config::get_nonzero_or("WS_MISSED_PONGS", 2),
+        }
+    }
+
+    // a timer that ticks whenever a client should be pinged, starting after one interval
+    pub fn pings(&self) -> tokio::time::Interval {
+        tokio::time::interval_at(tokio::time::Instant::now() + self.interval, self.interval)
+    }
+
+    // whether a client did not send anything, not even a pong, for too long
+    pub fn is_dead(&self, liveness: &Liveness) -> bool {
+        liveness.silence() >= self.interval * self.missed_pongs
+    }
+}
+
+// the last time a client was heard of
+#[derive(Debug)]
+pub struct Liveness {
+    last_seen: Mutex<Instant>,
+}
+
+impl Default for Liveness {
+    // a client that was just connected
+    fn default() -> Self {
+        Liveness {
+            last_seen: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl Liveness {
+    // remember that the client sent a frame, any frame proves the connection is alive
+    pub fn seen(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+
+    // time since the client last sent a frame
+    fn silence(&self) -> Duration {
+        self.last_seen.lock().unwrap().elapsed()
+    }
+}
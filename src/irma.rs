@@ -8,6 +8,7 @@ use std::convert::TryFrom;
 use std::fmt;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct SpecificAttribute {
     #[serde(rename = "type")]
     attribute_type: String,
@@ -15,11 +16,22 @@ pub struct SpecificAttribute {
     not_null: bool,
 }
 
+impl SpecificAttribute {
+    // an attribute that should be disclosed with a specific value
+    pub fn new(attribute_type: &str, value: &str) -> Self {
+        SpecificAttribute {
+            attribute_type: attribute_type.to_string(),
+            value: Some(value.to_string()),
+            not_null: true,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum Attribute {
     Simple(String),
-    _Specific(SpecificAttribute),
+    Specific(SpecificAttribute),
 }
 
 pub type ConDisCon = Vec<Vec<Vec<Attribute>>>;
@@ -150,7 +162,7 @@ impl TryFrom<String> for SessionStatus {
             "DONE" => SessionStatus::Done,
             "TIMEOUT" => SessionStatus::Timeout,
             _ => {
-                return Err(Error::ParseError(format!(
+                return Err(Error::Parse(format!(
                     "Could not parse SSE to sessions status '{}'",
                     &status
                 )))
@@ -161,13 +173,13 @@ impl TryFrom<String> for SessionStatus {
     }
 }
 
-type IrmaAttributes = HashMap<String, String>;
+pub type IrmaAttributes = HashMap<String, String>;
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct IrmaProofPayload {
-    attributes: IrmaAttributes,
+    pub attributes: IrmaAttributes,
     exp: i64,
-    iat: i64,
+    pub iat: i64,
     iss: String,
     status: ProofStatus,
     sub: String,
@@ -175,7 +187,7 @@ pub struct IrmaProofPayload {
 
 impl IrmaProofPayload {
     // request and verify the proof for an IRMA session
-    pub async fn verify(token: &str) -> Result<String, Error> {
+    pub async fn verify(token: &str) -> Result<IrmaProofPayload, Error> {
         // https://irma.app/docs/api-irma-server/#get-session-token-result-jwt
         let url = format!("{}/session/{}/getproof", config::get("IRMA_SERVER"), token);
         let response: String = reqwest::get(&url).await?.text().await?;
@@ -190,17 +202,22 @@ impl IrmaProofPayload {
             return Err(Error::InvalidProofStatus);
         }
 
-        let attributes: Vec<Vec<String>> = serde_json::from_str(&config::get("IRMA_ATTRIBUTES"))?;
-        let mut value: Vec<String> = vec![];
+        Ok(token_data)
+    }
+}
+
+// the username formed by the disclosed identifying attributes
+pub fn username(disclosed: &IrmaAttributes) -> Result<String, Error> {
+    let attributes: Vec<Vec<String>> = serde_json::from_str(&config::get("IRMA_ATTRIBUTES"))?;
+    let mut value: Vec<String> = vec![];
 
-        for attributes in attributes.into_iter() {
-            for attribute in attributes {
-                if let Some(part) = token_data.attributes.get(&attribute) {
-                    value.push(part.to_string());
-                }
+    for attributes in attributes.into_iter() {
+        for attribute in attributes {
+            if let Some(part) = disclosed.get(&attribute) {
+                value.push(part.to_string());
             }
         }
-
-        Ok(value.join(" "))
     }
+
+    Ok(value.join(" "))
 }
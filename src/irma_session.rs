@@ -1,7 +1,12 @@
+use crate::chat_room;
 use crate::config;
 use crate::errors::Error;
-use crate::errors::Error::ParseError;
-use crate::irma::{Attribute, IrmaProofPayload, IrmaRequest, SessionResponse, SessionStatus};
+use crate::errors::Error::Parse;
+use crate::irma::{
+    Attribute, ConDisCon, IrmaProofPayload, IrmaRequest, SessionResponse, SessionStatus,
+};
+use crate::pseudonym;
+use crate::roles;
 use futures_core::Stream;
 use futures_util::future::Ready;
 use futures_util::{self, future, StreamExt};
@@ -15,11 +20,52 @@ pub struct IrmaSession {
 }
 
 impl IrmaSession {
-    // create a new IRMA session
-    pub async fn new() -> Result<IrmaSession, Error> {
+    // the attributes requested when logging in
+    pub fn login_disclosure() -> Result<ConDisCon, Error> {
         let attributes: Vec<Vec<Attribute>> =
             serde_json::from_str(&config::get("IRMA_ATTRIBUTES"))?;
-        let disclosure = IrmaRequest::disclosure(vec![attributes]).as_jwt().await?;
+        let mut disclosure = vec![attributes];
+
+        // attributes that grant roles are optional, an empty conjunction discloses nothing
+        for attribute in roles::attributes() {
+            disclosure.push(vec![vec![], vec![Attribute::Simple(attribute)]]);
+        }
+
+        Ok(disclosure)
+    }
+
+    // the attributes requested when logging in under a pseudonym, attributes of gated rooms
+    // can be disclosed right away
+    pub fn pseudonymous_disclosure() -> Result<ConDisCon, Error> {
+        let attributes = pseudonym::attributes()
+            .ok_or_else(|| Error::Parse("Pseudonymous sessions are not enabled".to_string()))?;
+        let mut disclosure = vec![attributes];
+
+        for attribute in chat_room::gated_attributes() {
+            disclosure.push(vec![vec![], vec![Attribute::Simple(attribute)]]);
+        }
+
+        Ok(disclosure)
+    }
+
+    // the attributes requested when adding attributes to the session of a subject, the
+    // attributes that identify the subject are requested too so only its owner can disclose them
+    pub fn session_disclosure(sub: &str, requested: ConDisCon) -> Result<ConDisCon, Error> {
+        let identity = if pseudonym::is_pseudonym(sub) {
+            pseudonym::attributes()
+                .ok_or_else(|| Parse("Pseudonymous sessions are not enabled".to_string()))?
+        } else {
+            serde_json::from_str(&config::get("IRMA_ATTRIBUTES"))?
+        };
+        let mut disclosure = vec![identity];
+        disclosure.extend(requested);
+
+        Ok(disclosure)
+    }
+
+    // create a new IRMA session requesting the given attributes
+    pub async fn new(disclosure: ConDisCon) -> Result<IrmaSession, Error> {
+        let disclosure = IrmaRequest::disclosure(disclosure).as_jwt().await?;
         let url = format!("{}/session", config::get("IRMA_SERVER"));
         let client = reqwest::Client::new();
 
@@ -52,12 +98,12 @@ impl IrmaSession {
         Ok(())
     }
 
-    // retrieve the proof for the current session
-    pub async fn get_proof_payload(&self) -> Result<String, Error> {
+    // retrieve the verified proof of the current session
+    pub async fn get_proof_payload(&self) -> Result<IrmaProofPayload, Error> {
         info!("Verify proof of IRMA session: {}", &self.token);
-        let username = IrmaProofPayload::verify(&self.token).await?;
+        let proof = IrmaProofPayload::verify(&self.token).await?;
 
-        Ok(username)
+        Ok(proof)
     }
 
     // subscribe to SSE for session updates
@@ -84,10 +130,10 @@ impl IrmaSession {
     // parse an IRMA SSE to an IRMA SessionStatus
     fn parse_sse(event: Result<bytes::Bytes, reqwest::Error>) -> Result<SessionStatus, Error> {
         let bytes =
-            event.map_err(|e| ParseError(format!("Could not parse session status SSE {}", e)))?;
+            event.map_err(|e| Parse(format!("Could not parse session status SSE {}", e)))?;
 
         let msg: String = String::from_utf8(bytes.to_vec())
-            .map_err(|e| ParseError(format!("Could not parse session status SSE {}", e)))?;
+            .map_err(|e| Parse(format!("Could not parse session status SSE {}", e)))?;
 
         let mut parts = msg.splitn(2, ':');
 
@@ -99,7 +145,7 @@ impl IrmaSession {
             }
             _ => {
                 error!("Could not parse session status SSE {}", &msg);
-                return Err(ParseError("Could not parse session status SSE".to_string()));
+                return Err(Parse("Could not parse session status SSE".to_string()));
             }
         };
 
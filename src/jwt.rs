@@ -36,7 +36,7 @@ pub async fn encode_rsa<T: Serialize>(key_file: String, claim: T) -> Result<Stri
         &claim,
         &EncodingKey::from_rsa_pem(key.as_ref())?,
     )
-    .map_err(Error::JWTError)
+    .map_err(Error::JsonWebToken)
 }
 
 // decode and verify a JWT using RS256
@@ -49,7 +49,7 @@ pub async fn decode_rsa<T: DeserializeOwned>(key_file: String, token: String) ->
         &Validation::new(Algorithm::RS256),
     ) {
         Ok(result) => Ok(result.claims),
-        Err(e) => Err(Error::JWTError(e)),
+        Err(e) => Err(Error::JsonWebToken(e)),
     }
 }
 
@@ -64,17 +64,17 @@ pub fn encode<T: Serialize>(key: String, claim: T) -> Result<String, Error> {
         &claim,
         &EncodingKey::from_secret(key.as_ref()),
     )
-    .map_err(Error::JWTError)
+    .map_err(Error::JsonWebToken)
 }
 
-// decode and verify a JWT using HS256
-pub fn decode<T: DeserializeOwned>(key: String, token: String) -> Result<T, Error> {
-    match jsonwebtoken::decode::<T>(
-        &token,
-        &DecodingKey::from_secret(key.as_ref()),
-        &Validation::new(Algorithm::HS256),
-    ) {
+// decode and verify a JWT using HS256, with additional checks on the registered claims
+pub fn decode<T: DeserializeOwned>(
+    key: String,
+    token: String,
+    validation: &Validation,
+) -> Result<T, Error> {
+    match jsonwebtoken::decode::<T>(&token, &DecodingKey::from_secret(key.as_ref()), validation) {
         Ok(result) => Ok(result.claims),
-        Err(e) => Err(Error::JWTError(e)),
+        Err(e) => Err(Error::JsonWebToken(e)),
     }
 }
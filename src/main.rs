@@ -1,21 +1,43 @@
+mod attachments;
 mod auth_socket;
+mod badges;
+mod chat_response;
+mod chat_room;
 mod chat_socket;
+mod client_queue;
 mod config;
+mod content;
 mod errors;
+mod fanout;
+mod heartbeat;
 mod irma;
 mod irma_session;
 mod jwt;
+mod mentions;
+mod message_id;
+mod message_store;
+mod pseudonym;
+mod rate_limit;
+mod revocation;
+mod roles;
+mod search;
 mod session_jwt;
+mod shutdown;
 mod socket_request;
 mod socket_response;
+mod typing;
 
 #[macro_use]
 extern crate log;
 
-use crate::chat_socket::PeerMap;
+use crate::attachments::FileAttachments;
+use crate::chat_socket::ChatState;
+use crate::config::ChatConfig;
+use crate::message_store::SqliteStore;
+use crate::shutdown::Shutdown;
 use dotenv::dotenv;
-use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 
 // bind the chat and the authentication websocket to the provides ports
@@ -28,20 +50,48 @@ pub async fn serve() {
     let auth_listener = TcpListener::bind(auth_host).await.expect("Failed to bind");
     let chat_listener = TcpListener::bind(chat_host).await.expect("Failed to bind");
 
-    let state = PeerMap::new(Mutex::new(HashMap::new()));
+    let store = SqliteStore::open(&config::get_or("CHAT_DATABASE", "chat.sqlite"))
+        .expect("Failed to open the message store");
+    let attachments = FileAttachments::open(&config::get_or("CHAT_ATTACHMENTS", "attachments"))
+        .expect("Failed to open the attachment store");
+    let state = ChatState::new(
+        Arc::new(store),
+        Arc::new(attachments),
+        ChatConfig::from_env(),
+    );
 
-    // accept new ws connections and stop when receiving an interrupt
+    // accept new ws connections until the process is asked to stop
+    let shutdown = Shutdown::new();
+    let terminated = shutdown::terminated();
+    tokio::pin!(terminated);
     loop {
         tokio::select! {
             auth_stream = auth_listener.accept() => if let Ok((stream, _)) = auth_stream {
-                tokio::spawn(auth_socket::handle_auth_connection(stream));
+                tokio::spawn(auth_socket::handle_auth_connection(stream, shutdown.signal()));
             },
             chat_stream = chat_listener.accept() => if let Ok((stream, addr)) = chat_stream {
-                tokio::spawn(chat_socket::handle_chat_connection(state.clone(), stream, addr));
+                tokio::spawn(chat_socket::handle_chat_connection(
+                    state.clone(),
+                    stream,
+                    addr,
+                    shutdown.signal(),
+                ));
             },
-            _ = tokio::signal::ctrl_c() => break,
+            _ = &mut terminated => break,
         }
     }
+
+    // stop accepting connections and give the running ones some time to close
+    drop(auth_listener);
+    drop(chat_listener);
+
+    info!("Shutting down, closing all connections");
+    let deadline = Duration::from_secs(config::get_parsed_or("SHUTDOWN_TIMEOUT", 10));
+    if shutdown.drain(deadline).await {
+        info!("All connections were closed");
+    } else {
+        warn!("Not all connections were closed in time");
+    }
 }
 
 // application entry point
@@ -0,0 +1,99 @@
+use crate::pseudonym;
+
+// the number of "@" in a message that are resolved, later ones are ignored
+pub const MAX_MENTIONS: usize = 10;
+
+// the users mentioned in a message, i.e. "Hi @Foo Bar and @night-owl"
+#[derive(Debug, Default, PartialEq)]
+pub struct Mentions {
+    // the subjects that were mentioned
+    pub subjects: Vec<String>,
+    // mentioned names that match the display name of multiple subjects, with those subjects
+    pub ambiguous: Vec<(String, Vec<String>)>,
+}
+
+// the name shown for a subject, pseudonyms are shown without their stable id
+pub fn display_name(subject: &str) -> &str {
+    let id = pseudonym::stable_id(subject);
+
+    if id.len() < subject.len() {
+        &subject[..subject.len() - id.len()]
+    } else {
+        subject
+    }
+}
+
+// whether a text starts with a name that is not followed by more of a word,
+// i.e. "Foo, hi" starts with "Foo" but "Foobar" does not
+fn starts_with_name(text: &str, name: &str, ignore_case: bool) -> bool {
+    let start = match text.get(..name.len()) {
+        Some(start) => start,
+        None => return false,
+    };
+
+    let same = if ignore_case {
+        start.to_lowercase() == name.to_lowercase()
+    } else {
+        start == name
+    };
+    let boundary = text[name.len()..]
+        .chars()
+        .next()
+        .is_none_or(|c| !(c.is_alphanumeric() || c == '-' || c == '_'));
+
+    same && boundary
+}
+
+// resolve the "@name" mentions in a text to subjects, a subject is mentioned by its full name or,
+// case insensitive, by its display name when no other subject has the same display name, only
+// the first MAX_MENTIONS are resolved
+pub fn resolve(text: &str, subjects: &[String]) -> Mentions {
+    let mut mentions = Mentions::default();
+
+    for (position, _) in text.match_indices('@').take(MAX_MENTIONS) {
+        let rest = &text[position + 1..];
+
+        // the longest matching name wins, i.e. "@Foo Bar" mentions "Foo Bar" instead of "Foo"
+        let subject = subjects
+            .iter()
+            .filter(|subject| starts_with_name(rest, subject, false))
+            .max_by_key(|subject| subject.len());
+
+        let matching: Vec<String> = match subject {
+            Some(subject) => vec![subject.clone()],
+            None => {
+                let name = subjects
+                    .iter()
+                    .map(|subject| display_name(subject))
+                    .filter(|name| !name.is_empty() && starts_with_name(rest, name, true))
+                    .max_by_key(|name| name.len());
+
+                match name {
+                    Some(name) => subjects
+                        .iter()
+                        .filter(|subject| {
+                            display_name(subject).to_lowercase() == name.to_lowercase()
+                        })
+                        .cloned()
+                        .collect(),
+                    None => continue,
+                }
+            }
+        };
+
+        if matching.len() > 1 {
+            let name = display_name(&matching[0]).to_string();
+            if !mentions
+                .ambiguous
+                .iter()
+                .any(|(ambiguous, _)| *ambiguous == name)
+            {
+                mentions.ambiguous.push((name, matching));
+            }
+        } else if !mentions.subjects.contains(&matching[0]) {
+            mentions.subjects.push(matching[0].clone());
+        }
+    }
+
+    mentions
+}
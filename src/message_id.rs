@@ -0,0 +1,32 @@
+use chrono::Utc;
+use std::sync::Mutex;
+
+// number of bits reserved for messages created within the same millisecond
+const SEQUENCE_BITS: u32 = 10;
+
+// generator of unique message ids that are ordered by the time the messages were created,
+// the ids consist of the milliseconds since the epoch followed by a sequence number and fit
+// in a javascript number
+#[derive(Debug, Default)]
+pub struct MessageIds {
+    last: Mutex<i64>,
+}
+
+impl MessageIds {
+    // continue after the highest id in the history, so ids stay unique and ordered when the
+    // clock of a restarted server is behind
+    pub fn starting_after(last: i64) -> Self {
+        MessageIds {
+            last: Mutex::new(last),
+        }
+    }
+
+    // assign an id to a new message
+    pub fn next(&self) -> i64 {
+        let mut last = self.last.lock().unwrap();
+        let id = (Utc::now().timestamp_millis() << SEQUENCE_BITS).max(*last + 1);
+        *last = id;
+
+        id
+    }
+}
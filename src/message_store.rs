@@ -0,0 +1,740 @@
+use crate::attachments::Attachment;
+use crate::badges::Badge;
+use crate::content;
+use crate::errors::Error;
+use crate::search::SearchQuery;
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use std::sync::Mutex;
+
+// maximum number of characters of a single reaction
+const MAX_EMOJI_LENGTH: usize = 8;
+
+// the users that reacted to a message with the same emoji
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: usize,
+    pub users: Vec<String>,
+}
+
+// a chat message as kept in the message history
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredMessage {
+    pub id: i64,
+    pub room: String,
+    pub user: String,
+    pub time: i64,
+    pub msg: String,
+    pub edited: Option<i64>,
+    pub reply_to: Option<i64>,
+    pub reactions: Vec<Reaction>,
+    // the credentials the author disclosed when sending the message
+    pub badges: Vec<Badge>,
+    // the file sent with the message, if any
+    pub attachment: Option<Attachment>,
+}
+
+impl StoredMessage {
+    // add or remove the reaction of a user, reactions are kept in the order they were first used
+    pub fn react(&mut self, emoji: &str, user: &str, add: bool) {
+        let position = self.reactions.iter().position(|r| r.emoji == emoji);
+
+        match (position, add) {
+            (Some(i), true) => {
+                let reaction = &mut self.reactions[i];
+                if !reaction.users.iter().any(|u| u == user) {
+                    reaction.users.push(user.to_string());
+                    reaction.count = reaction.users.len();
+                }
+            }
+            (None, true) => self.reactions.push(Reaction {
+                emoji: emoji.to_string(),
+                count: 1,
+                users: vec![user.to_string()],
+            }),
+            (Some(i), false) => {
+                let reaction = &mut self.reactions[i];
+                reaction.users.retain(|u| u != user);
+                reaction.count = reaction.users.len();
+                if reaction.users.is_empty() {
+                    self.reactions.remove(i);
+                }
+            }
+            (None, false) => {}
+        }
+    }
+}
+
+// reactions should be short and can not contain text, nor the control and formatting
+// characters that are removed from messages
+pub fn is_valid_emoji(emoji: &str) -> bool {
+    content::sanitize(emoji, MAX_EMOJI_LENGTH).as_deref() == Ok(emoji)
+        && emoji
+            .chars()
+            .all(|c| !c.is_ascii_alphanumeric() && !c.is_whitespace())
+}
+
+// storage backend for the message history of all rooms
+pub trait MessageStore: Send + Sync {
+    // persist a new message, its id is assigned by the chat server
+    fn insert(&self, message: &StoredMessage) -> Result<(), Error>;
+
+    // retrieve a single message by its id
+    fn get(&self, id: i64) -> Result<Option<StoredMessage>, Error>;
+
+    // the highest id in the history, if there are any messages
+    fn last_id(&self) -> Result<Option<i64>, Error>;
+
+    // replace the text of a message, remembering when it was edited
+    fn edit(&self, id: i64, msg: &str, edited: i64) -> Result<(), Error>;
+
+    // remove a message and its reactions from the history
+    fn delete(&self, id: i64) -> Result<(), Error>;
+
+    // add or remove the reaction of a user to a message, returns the updated message
+    fn react(
+        &self,
+        id: i64,
+        emoji: &str,
+        user: &str,
+        add: bool,
+    ) -> Result<Option<StoredMessage>, Error>;
+
+    // retrieve a message and all (nested) replies to it, oldest first
+    fn thread(&self, id: i64) -> Result<Vec<StoredMessage>, Error>;
+
+    // retrieve at most `limit` messages of a room older than the `before` cursor, oldest first
+    fn history(
+        &self,
+        room: &str,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, Error>;
+
+    // retrieve at most `limit` messages of a room matching a query, older than the `before`
+    // cursor, oldest first
+    fn search(
+        &self,
+        room: &str,
+        query: &SearchQuery,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, Error>;
+
+    // the subjects of all authors in the history
+    fn authors(&self) -> Result<Vec<String>, Error>;
+
+    // remember that a message mentions a user, until the user read it
+    fn add_mention(&self, user: &str, id: i64) -> Result<(), Error>;
+
+    // retrieve the messages mentioning a user that it did not read, oldest first
+    fn unread_mentions(&self, user: &str) -> Result<Vec<StoredMessage>, Error>;
+
+    // mark the mention of a user in a message as read
+    fn read_mention(&self, user: &str, id: i64) -> Result<(), Error>;
+}
+
+// message history that only lives as long as the application, used for testing
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    messages: Mutex<Vec<StoredMessage>>,
+    // unread mentions, as the mentioned user and the id of the message
+    mentions: Mutex<Vec<(String, i64)>>,
+}
+
+impl MessageStore for MemoryStore {
+    fn insert(&self, message: &StoredMessage) -> Result<(), Error> {
+        self.messages.lock().unwrap().push(message.clone());
+
+        Ok(())
+    }
+
+    fn get(&self, id: i64) -> Result<Option<StoredMessage>, Error> {
+        let messages = self.messages.lock().unwrap();
+
+        Ok(messages.iter().find(|message| message.id == id).cloned())
+    }
+
+    fn last_id(&self) -> Result<Option<i64>, Error> {
+        let messages = self.messages.lock().unwrap();
+
+        Ok(messages.iter().map(|message| message.id).max())
+    }
+
+    fn edit(&self, id: i64, msg: &str, edited: i64) -> Result<(), Error> {
+        let mut messages = self.messages.lock().unwrap();
+
+        if let Some(message) = messages.iter_mut().find(|message| message.id == id) {
+            message.msg = msg.to_string();
+            message.edited = Some(edited);
+        }
+
+        Ok(())
+    }
+
+    fn delete(&self, id: i64) -> Result<(), Error> {
+        self.messages
+            .lock()
+            .unwrap()
+            .retain(|message| message.id != id);
+        self.mentions
+            .lock()
+            .unwrap()
+            .retain(|(_, message)| *message != id);
+
+        Ok(())
+    }
+
+    fn react(
+        &self,
+        id: i64,
+        emoji: &str,
+        user: &str,
+        add: bool,
+    ) -> Result<Option<StoredMessage>, Error> {
+        let mut messages = self.messages.lock().unwrap();
+        let message = messages.iter_mut().find(|message| message.id == id);
+
+        Ok(message.map(|message| {
+            message.react(emoji, user, add);
+            message.clone()
+        }))
+    }
+
+    fn thread(&self, id: i64) -> Result<Vec<StoredMessage>, Error> {
+        let messages = self.messages.lock().unwrap();
+        let mut thread: Vec<StoredMessage> = Vec::new();
+
+        // replies are always stored after the message they refer to
+        for message in messages.iter() {
+            let in_thread = message.id == id
+                || thread
+                    .iter()
+                    .any(|parent| message.reply_to == Some(parent.id));
+
+            if in_thread {
+                thread.push(message.clone());
+            }
+        }
+
+        Ok(thread)
+    }
+
+    fn history(
+        &self,
+        room: &str,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, Error> {
+        let before = before.unwrap_or(i64::MAX);
+        let messages = self.messages.lock().unwrap();
+        let mut page: Vec<StoredMessage> = messages
+            .iter()
+            .rev()
+            .filter(|message| message.room == room && message.id < before)
+            .take(limit)
+            .cloned()
+            .collect();
+        page.reverse();
+
+        Ok(page)
+    }
+
+    fn search(
+        &self,
+        room: &str,
+        query: &SearchQuery,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, Error> {
+        let before = before.unwrap_or(i64::MAX);
+        let messages = self.messages.lock().unwrap();
+        let mut page: Vec<StoredMessage> = messages
+            .iter()
+            .rev()
+            .filter(|message| message.room == room && message.id < before)
+            .filter(|message| query.matches(message))
+            .take(limit)
+            .cloned()
+            .collect();
+        page.reverse();
+
+        Ok(page)
+    }
+
+    fn authors(&self) -> Result<Vec<String>, Error> {
+        let mut authors: Vec<String> = self
+            .messages
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|message| message.user.clone())
+            .collect();
+        authors.sort();
+        authors.dedup();
+
+        Ok(authors)
+    }
+
+    fn add_mention(&self, user: &str, id: i64) -> Result<(), Error> {
+        let mut mentions = self.mentions.lock().unwrap();
+        let mention = (user.to_string(), id);
+
+        if !mentions.contains(&mention) {
+            mentions.push(mention);
+        }
+
+        Ok(())
+    }
+
+    fn unread_mentions(&self, user: &str) -> Result<Vec<StoredMessage>, Error> {
+        let mentions = self.mentions.lock().unwrap();
+        let messages = self.messages.lock().unwrap();
+
+        Ok(messages
+            .iter()
+            .filter(|message| mentions.contains(&(user.to_string(), message.id)))
+            .cloned()
+            .collect())
+    }
+
+    fn read_mention(&self, user: &str, id: i64) -> Result<(), Error> {
+        self.mentions
+            .lock()
+            .unwrap()
+            .retain(|mention| *mention != (user.to_string(), id));
+
+        Ok(())
+    }
+}
+
+// message history persisted in an embedded SQLite database
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+// a change of the database schema, applied once to every database
+enum Migration {
+    // statements that change the schema
+    Sql(&'static str),
+    // a column added to a table, databases created before versioning may already have it
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        definition: &'static str,
+    },
+}
+
+// the schema changes in order, the version of a database (its user_version) is the number of
+// changes that were applied to it, new changes are only ever appended
+const MIGRATIONS: &[Migration] = &[
+    // messages of rooms
+    Migration::Sql(
+        "CREATE TABLE IF NOT EXISTS messages (
+            id INTEGER PRIMARY KEY,
+            room TEXT NOT NULL,
+            user TEXT NOT NULL,
+            time INTEGER NOT NULL,
+            msg TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS messages_room ON messages (room, id);",
+    ),
+    // edited messages
+    Migration::AddColumn {
+        table: "messages",
+        column: "edited",
+        definition: "INTEGER",
+    },
+    // reactions to messages
+    Migration::Sql(
+        "CREATE TABLE IF NOT EXISTS reactions (
+            message INTEGER NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+            emoji TEXT NOT NULL,
+            user TEXT NOT NULL,
+            PRIMARY KEY (message, emoji, user)
+        );",
+    ),
+    // replies
+    Migration::AddColumn {
+        table: "messages",
+        column: "reply_to",
+        definition: "INTEGER",
+    },
+    Migration::Sql("CREATE INDEX IF NOT EXISTS messages_reply_to ON messages (reply_to);"),
+    // badges of the sender
+    Migration::AddColumn {
+        table: "messages",
+        column: "badges",
+        definition: "TEXT NOT NULL DEFAULT '[]'",
+    },
+    // attachments
+    Migration::AddColumn {
+        table: "messages",
+        column: "attachment",
+        definition: "TEXT",
+    },
+    // full-text search, kept up to date by triggers, messages stored before are indexed once
+    Migration::Sql(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts
+            USING fts5 (msg, content = 'messages', content_rowid = 'id');
+        CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+            INSERT INTO messages_fts (rowid, msg) VALUES (new.id, new.msg);
+        END;
+        CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+            INSERT INTO messages_fts (messages_fts, rowid, msg)
+            VALUES ('delete', old.id, old.msg);
+        END;
+        CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF msg ON messages BEGIN
+            INSERT INTO messages_fts (messages_fts, rowid, msg)
+            VALUES ('delete', old.id, old.msg);
+            INSERT INTO messages_fts (rowid, msg) VALUES (new.id, new.msg);
+        END;
+        INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');",
+    ),
+    // mentions
+    Migration::Sql(
+        "CREATE INDEX IF NOT EXISTS messages_user ON messages (user);
+        CREATE TABLE IF NOT EXISTS mentions (
+            user TEXT NOT NULL,
+            message INTEGER NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+            PRIMARY KEY (user, message)
+        );",
+    ),
+];
+
+// the version of the schema the application expects
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+impl Migration {
+    fn apply(&self, connection: &Connection) -> Result<(), Error> {
+        match self {
+            Migration::Sql(sql) => connection.execute_batch(sql)?,
+            Migration::AddColumn {
+                table,
+                column,
+                definition,
+            } => {
+                let exists: bool = connection.query_row(
+                    "SELECT EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2)",
+                    params![table, column],
+                    |row| row.get(0),
+                )?;
+
+                if !exists {
+                    connection.execute_batch(&format!(
+                        "ALTER TABLE {} ADD COLUMN {} {}",
+                        table, column, definition
+                    ))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SqliteStore {
+    // open (or create) the database at the given path, use ":memory:" for a temporary database
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let mut connection = Connection::open(path)?;
+        SqliteStore::migrate(&mut connection)?;
+        connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+
+        Ok(SqliteStore {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    // bring the schema of a database up to date, every change is applied in its own transaction
+    fn migrate(connection: &mut Connection) -> Result<(), Error> {
+        let version: i64 =
+            connection.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+        if version as usize > SCHEMA_VERSION {
+            return Err(Error::Parse(format!(
+                "Database schema version {} is newer than {}",
+                version, SCHEMA_VERSION
+            )));
+        }
+
+        for (version, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            info!("Migrating the database to schema version {}", version + 1);
+            let transaction = connection.transaction()?;
+            migration.apply(&transaction)?;
+            transaction.pragma_update(None, "user_version", &(version as i64 + 1))?;
+            transaction.commit()?;
+        }
+
+        Ok(())
+    }
+}
+
+// read a message from a row selected with all columns of the messages table,
+// its reactions are loaded separately
+fn message_from_row(row: &Row) -> Result<StoredMessage, rusqlite::Error> {
+    let badges: String = row.get(7)?;
+    let badges = serde_json::from_str(&badges)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(7, Type::Text, Box::new(e)))?;
+    let attachment: Option<String> = row.get(8)?;
+    let attachment = attachment
+        .map(|attachment| serde_json::from_str(&attachment))
+        .transpose()
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(8, Type::Text, Box::new(e)))?;
+
+    Ok(StoredMessage {
+        id: row.get(0)?,
+        room: row.get(1)?,
+        user: row.get(2)?,
+        time: row.get(3)?,
+        msg: row.get(4)?,
+        edited: row.get(5)?,
+        reply_to: row.get(6)?,
+        reactions: Vec::new(),
+        badges,
+        attachment,
+    })
+}
+
+// aggregate the reactions to a message in the order they were given
+fn load_reactions(connection: &Connection, message: &mut StoredMessage) -> Result<(), Error> {
+    let mut statement = connection
+        .prepare_cached("SELECT emoji, user FROM reactions WHERE message = ?1 ORDER BY rowid")?;
+    let rows = statement.query_map(params![message.id], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+
+    for row in rows {
+        let (emoji, user) = row?;
+        message.react(&emoji, &user, true);
+    }
+
+    Ok(())
+}
+
+// retrieve a single message and its reactions
+fn get_message(connection: &Connection, id: i64) -> Result<Option<StoredMessage>, Error> {
+    let message = connection
+        .query_row(
+            "SELECT id, room, user, time, msg, edited, reply_to, badges, attachment FROM messages
+            WHERE id = ?1",
+            params![id],
+            message_from_row,
+        )
+        .optional()?;
+
+    match message {
+        Some(mut message) => {
+            load_reactions(connection, &mut message)?;
+            Ok(Some(message))
+        }
+        None => Ok(None),
+    }
+}
+
+impl MessageStore for SqliteStore {
+    fn insert(&self, message: &StoredMessage) -> Result<(), Error> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO messages (id, room, user, time, msg, edited, reply_to, badges, attachment)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                message.id,
+                message.room,
+                message.user,
+                message.time,
+                message.msg,
+                message.edited,
+                message.reply_to,
+                serde_json::to_string(&message.badges)?,
+                message
+                    .attachment
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()?
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn get(&self, id: i64) -> Result<Option<StoredMessage>, Error> {
+        get_message(&self.connection.lock().unwrap(), id)
+    }
+
+    fn last_id(&self) -> Result<Option<i64>, Error> {
+        let connection = self.connection.lock().unwrap();
+
+        Ok(connection.query_row("SELECT MAX(id) FROM messages", params![], |row| row.get(0))?)
+    }
+
+    fn edit(&self, id: i64, msg: &str, edited: i64) -> Result<(), Error> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE messages SET msg = ?2, edited = ?3 WHERE id = ?1",
+            params![id, msg, edited],
+        )?;
+
+        Ok(())
+    }
+
+    fn delete(&self, id: i64) -> Result<(), Error> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM messages WHERE id = ?1", params![id])?;
+
+        Ok(())
+    }
+
+    fn react(
+        &self,
+        id: i64,
+        emoji: &str,
+        user: &str,
+        add: bool,
+    ) -> Result<Option<StoredMessage>, Error> {
+        let connection = self.connection.lock().unwrap();
+
+        if add {
+            connection.execute(
+                "INSERT OR IGNORE INTO reactions (message, emoji, user)
+                SELECT id, ?2, ?3 FROM messages WHERE id = ?1",
+                params![id, emoji, user],
+            )?;
+        } else {
+            connection.execute(
+                "DELETE FROM reactions WHERE message = ?1 AND emoji = ?2 AND user = ?3",
+                params![id, emoji, user],
+            )?;
+        }
+
+        get_message(&connection, id)
+    }
+
+    fn thread(&self, id: i64) -> Result<Vec<StoredMessage>, Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "WITH RECURSIVE thread (id) AS (
+                SELECT id FROM messages WHERE id = ?1
+                UNION SELECT messages.id FROM messages JOIN thread ON messages.reply_to = thread.id
+            )
+            SELECT id, room, user, time, msg, edited, reply_to, badges, attachment FROM messages
+            WHERE id IN thread ORDER BY id",
+        )?;
+        let rows = statement.query_map(params![id], message_from_row)?;
+
+        let mut thread = rows.collect::<Result<Vec<StoredMessage>, rusqlite::Error>>()?;
+        for message in thread.iter_mut() {
+            load_reactions(&connection, message)?;
+        }
+
+        Ok(thread)
+    }
+
+    fn history(
+        &self,
+        room: &str,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT id, room, user, time, msg, edited, reply_to, badges, attachment FROM messages
+            WHERE room = ?1 AND id < ?2 ORDER BY id DESC LIMIT ?3",
+        )?;
+        let rows = statement.query_map(
+            params![room, before.unwrap_or(i64::MAX), limit as i64],
+            message_from_row,
+        )?;
+
+        let mut page = rows.collect::<Result<Vec<StoredMessage>, rusqlite::Error>>()?;
+        page.reverse();
+        for message in page.iter_mut() {
+            load_reactions(&connection, message)?;
+        }
+
+        Ok(page)
+    }
+
+    fn search(
+        &self,
+        room: &str,
+        query: &SearchQuery,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT id, room, user, time, msg, edited, reply_to, badges, attachment FROM messages
+            WHERE room = ?1 AND id < ?2
+            AND (?3 IS NULL OR id IN (SELECT rowid FROM messages_fts WHERE messages_fts MATCH ?3))
+            AND (?4 IS NULL OR user = ?4)
+            AND (?5 IS NULL OR time >= ?5)
+            AND (?6 IS NULL OR time <= ?6)
+            ORDER BY id DESC LIMIT ?7",
+        )?;
+        let rows = statement.query_map(
+            params![
+                room,
+                before.unwrap_or(i64::MAX),
+                query.fts_query(),
+                query.user,
+                query.from,
+                query.until,
+                limit as i64
+            ],
+            message_from_row,
+        )?;
+
+        let mut page = rows.collect::<Result<Vec<StoredMessage>, rusqlite::Error>>()?;
+        page.reverse();
+        for message in page.iter_mut() {
+            load_reactions(&connection, message)?;
+        }
+
+        Ok(page)
+    }
+
+    fn authors(&self) -> Result<Vec<String>, Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement =
+            connection.prepare("SELECT DISTINCT user FROM messages ORDER BY user")?;
+        let rows = statement.query_map(params![], |row| row.get(0))?;
+
+        Ok(rows.collect::<Result<Vec<String>, rusqlite::Error>>()?)
+    }
+
+    fn add_mention(&self, user: &str, id: i64) -> Result<(), Error> {
+        self.connection.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO mentions (user, message) VALUES (?1, ?2)",
+            params![user, id],
+        )?;
+
+        Ok(())
+    }
+
+    fn unread_mentions(&self, user: &str) -> Result<Vec<StoredMessage>, Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT id, room, messages.user, time, msg, edited, reply_to, badges, attachment
+            FROM messages JOIN mentions ON mentions.message = messages.id
+            WHERE mentions.user = ?1 ORDER BY id",
+        )?;
+        let rows = statement.query_map(params![user], message_from_row)?;
+
+        let mut mentions = rows.collect::<Result<Vec<StoredMessage>, rusqlite::Error>>()?;
+        for message in mentions.iter_mut() {
+            load_reactions(&connection, message)?;
+        }
+
+        Ok(mentions)
+    }
+
+    fn read_mention(&self, user: &str, id: i64) -> Result<(), Error> {
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM mentions WHERE user = ?1 AND message = ?2",
+            params![user, id],
+        )?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+use crate::config;
+use crate::errors::Error;
+use crate::irma::{Attribute, IrmaAttributes};
+use ring::hmac;
+
+// number of bytes of the stable pseudonymous id that are shown in a pseudonym
+const TAG_LENGTH: usize = 6;
+
+// the name of pseudonymous users that did not pick one
+const DEFAULT_NAME: &str = "anonymous";
+
+// attributes that tie a pseudonym to a person without being shown or stored,
+// i.e. '[["pbdf.sidn-pbdf.email.email"]]', pseudonymous login is disabled when not configured
+pub fn attributes() -> Option<Vec<Vec<Attribute>>> {
+    let attributes = config::get_or("IRMA_PSEUDONYM_ATTRIBUTES", "");
+    if attributes.is_empty() {
+        return None;
+    }
+
+    Some(serde_json::from_str(&attributes).unwrap_or_else(|_| {
+        panic!("Fatal: the enviroment variable IRMA_PSEUDONYM_ATTRIBUTES is invalid.")
+    }))
+}
+
+// picked pseudonyms are short identifiers, i.e. "night-owl"
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 24
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// a keyed hash of the disclosed identifying attributes, the same person always gets the same
+// id while the attributes can not be derived from it
+fn pseudonymous_id(disclosed: &IrmaAttributes) -> Result<String, Error> {
+    let attributes: Vec<Vec<String>> =
+        serde_json::from_str(&config::get("IRMA_PSEUDONYM_ATTRIBUTES"))?;
+    let mut identity = String::new();
+
+    for attribute in attributes.into_iter().flatten() {
+        if let Some(value) = disclosed.get(&attribute) {
+            identity.push_str(&format!("{}={}\n", attribute, value));
+        }
+    }
+
+    if identity.is_empty() {
+        return Err(Error::Parse(
+            "No pseudonymous attributes were disclosed".to_string(),
+        ));
+    }
+
+    let key = hmac::Key::new(
+        hmac::HMAC_SHA256,
+        config::get("APP_PSEUDONYM_KEY").as_bytes(),
+    );
+    let tag = hmac::sign(&key, identity.as_bytes());
+
+    Ok(tag.as_ref()[..TAG_LENGTH]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+// the pseudonym of a user, i.e. "night-owl#3fa2c91b07de", the picked name is followed by the
+// start of its stable pseudonymous id so nobody can pose as someone else
+pub fn pseudonym(name: Option<&str>, disclosed: &IrmaAttributes) -> Result<String, Error> {
+    let name = name.unwrap_or(DEFAULT_NAME);
+    if !is_valid_name(name) {
+        return Err(Error::Parse(format!("Invalid pseudonym: {}", name)));
+    }
+
+    Ok(format!("{}#{}", name, pseudonymous_id(disclosed)?))
+}
+
+// whether a subject is a pseudonym instead of a name formed by identifying attributes
+pub fn is_pseudonym(sub: &str) -> bool {
+    stable_id(sub) != sub
+}
+
+// whether the disclosed identifying attributes belong to the person behind a pseudonym
+pub fn belongs_to(sub: &str, disclosed: &IrmaAttributes) -> Result<bool, Error> {
+    Ok(is_pseudonym(sub) && stable_id(sub)[1..] == pseudonymous_id(disclosed)?)
+}
+
+// the part of a subject that identifies a person, pseudonymous users can pick another name but
+// keep their id, so bans and revocations apply to all their pseudonyms
+pub fn stable_id(sub: &str) -> &str {
+    let id = match sub.rfind('#') {
+        Some(position) => &sub[position..],
+        None => return sub,
+    };
+
+    if id.len() == TAG_LENGTH * 2 + 1 && id[1..].chars().all(|c| c.is_ascii_hexdigit()) {
+        id
+    } else {
+        sub
+    }
+}
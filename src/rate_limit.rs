@@ -0,0 +1,169 @@
+use crate::pseudonym;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// size and refill rate of a token bucket
+#[derive(Debug, Clone, Copy)]
+pub struct BucketLimit {
+    // maximum number of requests that can be sent at once
+    pub burst: u32,
+    // number of requests per second that are added to the bucket
+    pub refill: f64,
+}
+
+// tokens that are spent by requests and refilled over time
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: BucketLimit, now: Instant) -> Self {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, limit: BucketLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.refill).min(limit.burst as f64);
+        self.updated_at = now;
+    }
+
+    fn has_token(&self) -> bool {
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+}
+
+// flood control settings of the chat server
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimits {
+    pub connection: BucketLimit,
+    pub subject: BucketLimit,
+    // number of overflows after which a user is muted
+    pub strikes: u32,
+    // time a user is muted, overflows older than this are forgiven
+    pub mute_duration: Duration,
+}
+
+// the outcome of checking a request against the rate limits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimit {
+    // the request can be handled
+    Allowed,
+    // the first overflow, the request is dropped and the client should be warned
+    Warned,
+    // the request is dropped silently
+    Dropped,
+    // repeated overflows, the user is muted until the given time
+    Muted(Instant),
+}
+
+// administration of overflows of a subject
+#[derive(Debug)]
+struct Offender {
+    bucket: TokenBucket,
+    strikes: u32,
+    struck_at: Option<Instant>,
+}
+
+// token bucket rate limiting per connection and per subject, all pseudonyms of a person share
+// the limits and mutes of its stable id
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    connections: HashMap<SocketAddr, TokenBucket>,
+    subjects: HashMap<String, Offender>,
+    mutes: HashMap<String, Instant>,
+}
+
+impl RateLimiter {
+    // spend a token of both the connection and the subject for a request
+    pub fn check(
+        &mut self,
+        addr: &SocketAddr,
+        sub: &str,
+        limits: &RateLimits,
+        now: Instant,
+    ) -> RateLimit {
+        let connection = self
+            .connections
+            .entry(*addr)
+            .or_insert_with(|| TokenBucket::new(limits.connection, now));
+        let offender = self
+            .subjects
+            .entry(pseudonym::stable_id(sub).to_string())
+            .or_insert_with(|| Offender {
+                bucket: TokenBucket::new(limits.subject, now),
+                strikes: 0,
+                struck_at: None,
+            });
+
+        connection.refill(limits.connection, now);
+        offender.bucket.refill(limits.subject, now);
+
+        if connection.has_token() && offender.bucket.has_token() {
+            connection.take();
+            offender.bucket.take();
+
+            return RateLimit::Allowed;
+        }
+
+        // forgive overflows that happened long ago
+        if let Some(struck_at) = offender.struck_at {
+            if now.saturating_duration_since(struck_at) > limits.mute_duration {
+                offender.strikes = 0;
+            }
+        }
+        offender.strikes += 1;
+        offender.struck_at = Some(now);
+
+        if offender.strikes >= limits.strikes {
+            let until = now + limits.mute_duration;
+            offender.strikes = 0;
+            self.mute(sub, until);
+
+            RateLimit::Muted(until)
+        } else if offender.strikes == 1 {
+            RateLimit::Warned
+        } else {
+            RateLimit::Dropped
+        }
+    }
+
+    // prevent a user from posting until the given time
+    pub fn mute(&mut self, sub: &str, until: Instant) {
+        self.mutes
+            .insert(pseudonym::stable_id(sub).to_string(), until);
+    }
+
+    // the time until which a user is muted, if it is muted
+    pub fn muted_until(&self, sub: &str, now: Instant) -> Option<Instant> {
+        self.mutes
+            .get(pseudonym::stable_id(sub))
+            .copied()
+            .filter(|until| *until > now)
+    }
+
+    // forget a closed connection and all subjects that are back to normal
+    pub fn remove_connection(&mut self, addr: &SocketAddr, limits: &RateLimits, now: Instant) {
+        self.connections.remove(addr);
+
+        self.mutes.retain(|_, until| *until > now);
+        self.subjects.retain(|_, offender| {
+            offender.bucket.refill(limits.subject, now);
+
+            let recently_struck = offender.struck_at.is_some_and(|struck_at| {
+                now.saturating_duration_since(struck_at) <= limits.mute_duration
+            });
+
+            offender.bucket.tokens < limits.subject.burst as f64 || recently_struck
+        });
+    }
+}
@@ -0,0 +1,80 @@
+use crate::errors::Error;
+use crate::pseudonym;
+use crate::session_jwt::SessionJwt;
+use chrono::Utc;
+use std::collections::HashMap;
+
+// in memory administration of session tokens that were invalidated before they expired
+#[derive(Debug, Default)]
+pub struct RevocationList {
+    // revoked token ids, mapped to the expiry time of the token
+    tokens: HashMap<String, i64>,
+    // subjects for which all tokens issued up to the mapped time are revoked, pseudonymous
+    // subjects are identified by their stable id
+    subjects: HashMap<String, i64>,
+    // subjects that can not start new sessions until the mapped time
+    bans: HashMap<String, i64>,
+}
+
+impl RevocationList {
+    // revoke a single session token
+    pub fn revoke_token(&mut self, jwt: &SessionJwt) {
+        self.prune();
+        self.tokens.insert(jwt.jti.clone(), jwt.exp);
+    }
+
+    // revoke every session token that was issued to a subject until now
+    pub fn revoke_subject(&mut self, sub: &str) {
+        self.prune();
+        self.subjects.insert(
+            pseudonym::stable_id(sub).to_string(),
+            Utc::now().timestamp(),
+        );
+    }
+
+    // revoke all sessions of a subject and refuse its new sessions until the given time
+    pub fn ban_subject(&mut self, sub: &str, until: i64) {
+        self.revoke_subject(sub);
+        self.bans
+            .insert(pseudonym::stable_id(sub).to_string(), until);
+    }
+
+    // check whether a subject is currently banned
+    pub fn is_banned(&self, sub: &str) -> bool {
+        let until = self.bans.get(pseudonym::stable_id(sub));
+
+        matches!(until, Some(until) if *until > Utc::now().timestamp())
+    }
+
+    // check whether a session token was revoked
+    pub fn is_revoked(&self, jwt: &SessionJwt) -> bool {
+        self.tokens.contains_key(&jwt.jti)
+            || matches!(
+                self.subjects.get(pseudonym::stable_id(&jwt.sub)),
+                Some(revoked_at) if jwt.iat <= *revoked_at
+            )
+    }
+
+    // pass a session token through when it was not revoked
+    pub fn check(&self, jwt: SessionJwt) -> Result<SessionJwt, Error> {
+        if self.is_banned(&jwt.sub) {
+            return Err(Error::BannedSubject);
+        }
+
+        if self.is_revoked(&jwt) {
+            return Err(Error::RevokedJWT);
+        }
+
+        Ok(jwt)
+    }
+
+    // forget revocations of tokens that would have expired by now anyway
+    fn prune(&mut self) {
+        let now = Utc::now().timestamp();
+
+        self.tokens.retain(|_, exp| *exp >= now);
+        self.subjects
+            .retain(|_, revoked_at| *revoked_at + SessionJwt::TTL >= now);
+        self.bans.retain(|_, until| *until > now);
+    }
+}
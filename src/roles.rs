@@ -0,0 +1,75 @@
+use crate::config;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// additional permissions of a user in the chat
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    // may moderate users and view the server metrics
+    Admin,
+    // may kick, mute and ban users and remove messages
+    Moderator,
+}
+
+// grants a role to users that disclosed a matching attribute,
+// i.e. {"role":"moderator","attribute":"pbdf.sidn-pbdf.email.domain","value":"example.com"}
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct RoleRule {
+    pub role: Role,
+    pub attribute: String,
+    // the exact value of the attribute, any value matches when omitted
+    pub value: Option<String>,
+    // case insensitive ending of the value, i.e. "@example.com" for an email address
+    pub suffix: Option<String>,
+}
+
+impl RoleRule {
+    // check whether the disclosed attributes satisfy this rule
+    fn matches(&self, attributes: &HashMap<String, String>) -> bool {
+        let disclosed = match attributes.get(&self.attribute) {
+            Some(disclosed) => disclosed,
+            None => return false,
+        };
+
+        let value_matches = self.value.as_ref().is_none_or(|value| value == disclosed);
+        let suffix_matches = self
+            .suffix
+            .as_ref()
+            .is_none_or(|suffix| disclosed.to_lowercase().ends_with(&suffix.to_lowercase()));
+
+        value_matches && suffix_matches
+    }
+}
+
+// the role rules of this deployment
+pub fn rules() -> Vec<RoleRule> {
+    serde_json::from_str(&config::get_or("APP_ROLES", "[]"))
+        .unwrap_or_else(|_| panic!("Fatal: the enviroment variable APP_ROLES is invalid."))
+}
+
+// the attributes that are (optionally) requested to determine the roles of a user
+pub fn attributes() -> Vec<String> {
+    let mut attributes: Vec<String> = Vec::new();
+
+    for rule in rules() {
+        if !attributes.contains(&rule.attribute) {
+            attributes.push(rule.attribute);
+        }
+    }
+
+    attributes
+}
+
+// the roles granted by the attributes a user disclosed
+pub fn granted(attributes: &HashMap<String, String>) -> Vec<Role> {
+    let mut roles: Vec<Role> = Vec::new();
+
+    for rule in rules() {
+        if rule.matches(attributes) && !roles.contains(&rule.role) {
+            roles.push(rule.role);
+        }
+    }
+
+    roles
+}
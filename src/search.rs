@@ -0,0 +1,61 @@
+use crate::message_store::StoredMessage;
+use serde::Deserialize;
+
+// criteria to search the history of a room with, all given criteria should match,
+// i.e. {"query":"rust async","user":"Foo Bar","from":1612137600}
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SearchQuery {
+    // keywords that should all occur in a message, matching the start of words
+    pub query: Option<String>,
+    // the author of the messages
+    pub user: Option<String>,
+    // the oldest time (unix timestamp) a message was sent
+    pub from: Option<i64>,
+    // the newest time (unix timestamp) a message was sent
+    pub until: Option<i64>,
+}
+
+// split text in lowercase words, as the full-text index does
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+}
+
+impl SearchQuery {
+    // the keywords of the query
+    pub fn keywords(&self) -> Vec<String> {
+        self.query
+            .as_deref()
+            .map(|query| words(query).collect())
+            .unwrap_or_default()
+    }
+
+    // the keywords as a full-text query, every keyword is quoted so the text of the user is
+    // never interpreted as query syntax, none when no keywords were given
+    pub fn fts_query(&self) -> Option<String> {
+        let keywords = self.keywords();
+        if keywords.is_empty() {
+            return None;
+        }
+
+        let terms: Vec<String> = keywords
+            .iter()
+            .map(|keyword| format!("\"{}\"*", keyword.replace('"', "\"\"")))
+            .collect();
+
+        Some(terms.join(" "))
+    }
+
+    // check whether a message matches all criteria, for stores without a full-text index
+    pub fn matches(&self, message: &StoredMessage) -> bool {
+        let words: Vec<String> = words(&message.msg).collect();
+
+        self.keywords()
+            .iter()
+            .all(|keyword| words.iter().any(|word| word.starts_with(keyword.as_str())))
+            && self.user.as_ref().is_none_or(|user| *user == message.user)
+            && self.from.is_none_or(|from| message.time >= from)
+            && self.until.is_none_or(|until| message.time <= until)
+    }
+}
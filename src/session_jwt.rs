@@ -1,24 +1,81 @@
+use crate::badges::Badge;
 use crate::config;
 use crate::errors::Error;
-use crate::jwt::encode;
+use crate::jwt::{decode, encode};
+use crate::roles::Role;
 use chrono::Utc;
+use jsonwebtoken::{Algorithm, Validation};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use uuid::Uuid;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SessionJwt {
+    pub iss: String,
+    pub aud: String,
     pub exp: i64,
+    pub iat: i64,
+    pub nbf: i64,
+    pub jti: String,
     pub sub: String,
+    // roles granted by the attributes disclosed when the session was started
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<Role>,
+    // disclosed attributes that give access to gated rooms
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub attributes: BTreeMap<String, String>,
+    // the credentials that were disclosed, shown to other users with every message
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub badges: Vec<Badge>,
 }
 
 impl SessionJwt {
+    // lifetime of a chat session in seconds
+    pub const TTL: i64 = 3600;
+
     // create a new chat application session clains
     pub fn new(sub: String) -> Self {
+        SessionJwt::with_roles(sub, Vec::new())
+    }
+
+    // create new session claims for a user with additional permissions
+    pub fn with_roles(sub: String, roles: Vec<Role>) -> Self {
+        let now = Utc::now().timestamp();
+
         SessionJwt {
-            exp: Utc::now().timestamp() + 3600,
+            iss: SessionJwt::issuer(),
+            aud: SessionJwt::audience(),
+            exp: now + SessionJwt::TTL,
+            iat: now,
+            nbf: now,
+            jti: Uuid::new_v4().to_string(),
             sub,
+            roles,
+            attributes: BTreeMap::new(),
+            badges: Vec::new(),
         }
     }
 
+    // whether the user has a role, admins can do everything moderators can
+    pub fn has_role(&self, role: Role) -> bool {
+        self.roles.contains(&role) || self.roles.contains(&Role::Admin)
+    }
+
+    // the deployment issuing session tokens
+    fn issuer() -> String {
+        config::get("APP_NAME")
+    }
+
+    // the service session tokens are meant for
+    fn audience() -> String {
+        config::get_or("APP_JWT_AUDIENCE", "chat")
+    }
+
+    // allowed clock skew in seconds when checking the token timestamps
+    fn leeway() -> u64 {
+        config::get_parsed_or("APP_JWT_LEEWAY", 0)
+    }
+
     // encode and sign claims as a JWT
     pub fn as_jwt(&self) -> Result<String, Error> {
         let key = config::get("APP_JWT_KEY");
@@ -26,4 +83,25 @@ impl SessionJwt {
 
         Ok(jwt)
     }
+
+    // decode and verify a JWT, only accepting tokens issued by and meant for this deployment
+    pub fn from_jwt(token: String) -> Result<Self, Error> {
+        let key = config::get("APP_JWT_KEY");
+        let leeway = SessionJwt::leeway();
+
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = leeway;
+        validation.validate_nbf = true;
+        validation.iss = Some(SessionJwt::issuer());
+        validation.set_audience(&[SessionJwt::audience()]);
+
+        let jwt: SessionJwt = decode(key, token, &validation)?;
+
+        // tokens can not be issued in the future
+        if jwt.iat > Utc::now().timestamp() + leeway as i64 {
+            return Err(Error::InvalidJWT);
+        }
+
+        Ok(jwt)
+    }
 }
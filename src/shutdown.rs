@@ -0,0 +1,96 @@
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Message};
+
+// the close reason that tells clients the server is going away and they should connect again
+pub const RECONNECT: &str = "reconnect";
+
+// stops running connections and waits until they are done
+pub struct Shutdown {
+    notify: watch::Sender<bool>,
+    signal: ShutdownSignal,
+    running: mpsc::Receiver<()>,
+}
+
+// held by every running connection, tells it when the server is shutting down
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    shutting_down: watch::Receiver<bool>,
+    // the server waits until all clones of this sender are dropped
+    _running: mpsc::Sender<()>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (notify, shutting_down) = watch::channel(false);
+        let (running, wait_for_running) = mpsc::channel(1);
+
+        Shutdown {
+            notify,
+            signal: ShutdownSignal {
+                shutting_down,
+                _running: running,
+            },
+            running: wait_for_running,
+        }
+    }
+
+    // a signal for a new connection, the connection is awaited until it drops the signal
+    pub fn signal(&self) -> ShutdownSignal {
+        self.signal.clone()
+    }
+
+    // tell all connections to stop and wait until they are done, returns false when some
+    // connections were still running at the deadline
+    pub async fn drain(self, deadline: Duration) -> bool {
+        let Shutdown {
+            notify,
+            signal,
+            mut running,
+        } = self;
+
+        let _ = notify.send(true);
+        drop(signal);
+
+        tokio::time::timeout(deadline, running.recv()).await.is_ok()
+    }
+}
+
+impl ShutdownSignal {
+    // resolves when the server is shutting down, never resolves when the server is not
+    pub async fn recv(&mut self) {
+        while !*self.shutting_down.borrow() {
+            if self.shutting_down.changed().await.is_err() {
+                futures_util::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+// the close frame sent to clients when the server shuts down
+pub fn going_away() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::Away,
+        reason: RECONNECT.into(),
+    }))
+}
+
+// resolves when the process is asked to stop, by SIGINT (ctrl-c) or SIGTERM
+#[cfg(unix)]
+pub async fn terminated() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+
+    tokio::select! {
+        _ = terminate.recv() => {},
+        _ = tokio::signal::ctrl_c() => {},
+    }
+}
+
+// resolves when the process is asked to stop by ctrl-c
+#[cfg(not(unix))]
+pub async fn terminated() {
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -1,8 +1,152 @@
+use crate::chat_room::Requirement;
 use crate::errors::Error;
+use crate::search::SearchQuery;
+use serde::Deserialize;
 use std::fmt::Display;
 use tokio_tungstenite::tungstenite;
 use tokio_tungstenite::tungstenite::Message;
 
+// version of the chat socket protocol, sent as "v" with every request
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// typed request of a chat client, i.e. {"v":1,"type":"send","room":"general","text":"Hello"}
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatRequest {
+    // authenticate using a session JWT, this should be the first request
+    Auth {
+        token: String,
+    },
+    // send a message to a joined room, or the default room, optionally as reply to a message
+    Send {
+        room: Option<String>,
+        text: String,
+        reply_to: Option<i64>,
+    },
+    // send a private message to all sessions of a user
+    Direct {
+        to: String,
+        text: String,
+    },
+    // replace the text of an own message in the history
+    Edit {
+        id: i64,
+        text: String,
+    },
+    // remove an own message from the history
+    Delete {
+        id: i64,
+    },
+    // announce an attachment for a joined room, or the default room, its content follows in
+    // binary frames
+    Upload {
+        room: Option<String>,
+        name: String,
+        mime: String,
+        size: usize,
+        text: Option<String>,
+    },
+    // retrieve the content of the attachment of a message, sent in binary frames
+    Download {
+        id: i64,
+    },
+    // add or remove a reaction to a message in a joined room
+    React {
+        id: i64,
+        emoji: String,
+        add: bool,
+    },
+    // join a room and receive its recent history
+    Join {
+        room: String,
+    },
+    // leave a room
+    Leave {
+        room: String,
+    },
+    // list the available rooms
+    Rooms,
+    // retrieve a page of older messages of a joined room
+    History {
+        room: String,
+        before: Option<i64>,
+    },
+    // retrieve a message and all replies to it
+    Thread {
+        id: i64,
+    },
+    // retrieve a page of messages of a joined room matching keywords, an author or a time range
+    Search {
+        room: String,
+        #[serde(flatten)]
+        query: SearchQuery,
+        before: Option<i64>,
+    },
+    // indicate the user started or stopped typing in a room
+    Typing {
+        room: String,
+        typing: bool,
+    },
+    // acknowledge that a message, or a batch of messages, was received
+    Ack {
+        id: Option<i64>,
+        #[serde(default)]
+        ids: Vec<i64>,
+    },
+    // check whether the connection is still alive
+    Ping,
+    // end all sessions of the current user and revoke the session JWT
+    Logout,
+    // close all sessions of a user, it can connect again (moderators only)
+    Kick {
+        subject: String,
+    },
+    // prevent a user from posting for a number of seconds (moderators only)
+    Mute {
+        subject: String,
+        seconds: u64,
+    },
+    // close all sessions of a user and refuse new sessions, permanently or for a number of
+    // seconds (moderators only)
+    Ban {
+        subject: String,
+        seconds: Option<u64>,
+    },
+    // remove any message from the history (moderators only)
+    Remove {
+        id: i64,
+    },
+    // retrieve the outbound queue metrics of the server (admins only)
+    Metrics,
+    // end and revoke all sessions of a user (admins only)
+    Revoke {
+        subject: String,
+    },
+}
+
+// typed request on the authentication socket, besides the plain "start" and "stop",
+// i.e. {"action":"pseudonymous","pseudonym":"night-owl"}
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AuthRequest {
+    // start an IRMA session to log in under a pseudonym, without disclosing a name
+    Pseudonymous {
+        pseudonym: Option<String>,
+    },
+    // start an IRMA session to add the attributes required by a gated room to a session JWT
+    Disclose {
+        token: String,
+        attributes: Vec<Requirement>,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+struct VersionedRequest {
+    v: u32,
+    #[serde(flatten)]
+    request: ChatRequest,
+}
+
 #[derive(Debug)]
 pub struct SocketRequest(Message);
 
@@ -13,12 +157,19 @@ impl Display for SocketRequest {
     }
 }
 
+impl From<Message> for SocketRequest {
+    // wrap a ws client message that was already received
+    fn from(message: Message) -> Self {
+        SocketRequest(message)
+    }
+}
+
 impl SocketRequest {
     // create an abstraction over a ws client message
     pub fn from_message(
         message: Option<Result<Message, tungstenite::Error>>,
     ) -> Result<Self, Error> {
-        Ok(SocketRequest(message.ok_or(Error::IgnorableError)??))
+        Ok(SocketRequest(message.ok_or(Error::Ignorable)??))
     }
 
     // request to start a new IRMA session
@@ -31,8 +182,58 @@ impl SocketRequest {
         self.0.to_string() == "stop"
     }
 
+    // parse an authentication client message as a typed request
+    pub fn auth_request(&self) -> Result<AuthRequest, Error> {
+        Ok(serde_json::from_str(self.0.to_text()?)?)
+    }
+
+    // the kind of an authentication client message, which can be logged without the tokens
+    // or attribute values it may contain
+    pub fn action(&self) -> &'static str {
+        if self.is_start() {
+            return "start";
+        }
+        if self.is_stop() {
+            return "stop";
+        }
+
+        match &self.0 {
+            Message::Ping(_) | Message::Pong(_) => "heartbeat",
+            Message::Close(_) => "close",
+            _ => match self.auth_request() {
+                Ok(AuthRequest::Pseudonymous { .. }) => "pseudonymous",
+                Ok(AuthRequest::Disclose { .. }) => "disclose",
+                Err(_) => "unknown",
+            },
+        }
+    }
+
+    // ping or pong frame, only used to check whether the connection is alive
+    pub fn is_heartbeat(&self) -> bool {
+        self.0.is_ping() || self.0.is_pong()
+    }
+
+    // the content of a binary frame, i.e. a chunk of an attachment
+    pub fn binary(&self) -> Option<&[u8]> {
+        match &self.0 {
+            Message::Binary(data) => Some(data),
+            _ => None,
+        }
+    }
+
     // message indicating the connetion was closed
     pub fn is_close(&self) -> bool {
         self.0.is_close()
     }
+
+    // parse a chat client message as a typed request
+    pub fn chat_request(&self) -> Result<ChatRequest, Error> {
+        let versioned: VersionedRequest = serde_json::from_str(self.0.to_text()?)?;
+
+        if versioned.v != PROTOCOL_VERSION {
+            return Err(Error::UnsupportedVersion(versioned.v));
+        }
+
+        Ok(versioned.request)
+    }
 }
@@ -45,6 +45,11 @@ impl SocketResponse {
         }
     }
 
+    // the kind of response, which can be logged without its payload
+    pub fn action(&self) -> &'static str {
+        self.action
+    }
+
     // encode a response as (json) string
     pub fn encode(&self) -> Result<String, Error> {
         Ok(serde_json::to_string(&self)?)
@@ -1,26 +1,47 @@
-use crate::jwt::{decode, encode, encode_rsa};
+use crate::jwt::{encode, encode_rsa};
 use crate::session_jwt::SessionJwt;
 use crate::{auth_socket, chat_socket, config};
 use chrono::Utc;
 use dotenv::dotenv;
 
-use crate::chat_socket::PeerMap;
+use crate::attachments::{self, Attachment, AttachmentLimits, MemoryAttachments};
+use crate::badges::Badge;
+use crate::chat_response::{ChatMessage, ChatResponse};
+use crate::chat_socket::ChatState;
+use crate::client_queue::{self, QueueMetrics, QueuePolicy, QueueStats};
+use crate::config::ChatConfig;
+use crate::errors::Error;
+use crate::fanout::{Member, RoomDirectory};
+use crate::heartbeat::Heartbeat;
+use crate::mentions;
+use crate::message_store::{self, MemoryStore, MessageStore, Reaction, SqliteStore, StoredMessage};
+use crate::rate_limit::{BucketLimit, RateLimits};
+use crate::roles::Role;
+use crate::search::SearchQuery;
+use crate::shutdown::Shutdown;
+use crate::socket_request::SocketRequest;
+use crate::socket_response::SocketResponse;
 use serde::Deserialize;
-use serde_json::json;
-use std::collections::HashMap;
+use serde_json::{json, Value};
 use std::env;
-use std::sync::Mutex;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use tokio::net::TcpListener;
-use tokio_tungstenite::tungstenite::connect;
+use tokio_tungstenite::tungstenite::client::AutoStream;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::{connect, Message, WebSocket};
 
 #[derive(Deserialize)]
 struct Action {
     payload: String,
 }
 
-async fn init_session() -> mockito::Mock {
+async fn init_session() -> (mockito::Mock, String) {
     dotenv().ok();
-    env::set_var("IRMA_SERVER", &mockito::server_url());
+    env::set_var("IRMA_SERVER", mockito::server_url());
 
     let start_mock = mockito::mock("POST", "/session")
         .with_status(200)
@@ -38,27 +59,90 @@ async fn init_session() -> mockito::Mock {
         )
         .create();
 
-    tokio::spawn(async {
-        let auth_host = config::get("WS_HOST");
-        let auth_listener = TcpListener::bind(auth_host).await.expect("Failed to bind");
+    let auth_listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind");
+    let url = format!("ws://{}", auth_listener.local_addr().unwrap());
+
+    // the server of the tests never shuts down
+    let shutdown = Shutdown::new().signal();
+    tokio::spawn(async move {
         while let Ok((stream, _)) = auth_listener.accept().await {
-            tokio::spawn(auth_socket::handle_auth_connection(stream));
+            tokio::spawn(auth_socket::handle_auth_connection(
+                stream,
+                shutdown.clone(),
+            ));
+        }
+    });
+
+    (start_mock, url)
+}
+
+async fn init_chat() -> String {
+    dotenv().ok();
+    init_chat_with(ChatState::default()).await
+}
+
+async fn init_chat_with(state: ChatState) -> String {
+    let chat_listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind");
+    let url = format!("ws://{}", chat_listener.local_addr().unwrap());
+
+    let shutdown = Shutdown::new().signal();
+    tokio::spawn(async move {
+        while let Ok((stream, addr)) = chat_listener.accept().await {
+            tokio::spawn(chat_socket::handle_chat_connection(
+                state.clone(),
+                stream,
+                addr,
+                shutdown.clone(),
+            ));
         }
     });
 
-    start_mock
+    url
+}
+
+fn gate_rooms() {
+    env::set_var(
+        "CHAT_ROOMS",
+        r#"{"adults":[{"attribute":"pbdf.gemeente.personalData.over18","value":"Yes"}]}"#,
+    );
+}
+
+fn chat_request(mut request: Value) -> Message {
+    request["v"] = json!(1);
+    request.to_string().into()
+}
+
+fn read_json(socket: &mut WebSocket<AutoStream>) -> Value {
+    serde_json::from_str(&socket.read_message().unwrap().to_string()).unwrap()
+}
+
+fn connect_chat(url: &str, jwt: &str) -> WebSocket<AutoStream> {
+    let (mut socket, _) = connect(url).expect("Failed to connect");
+    socket
+        .write_message(chat_request(json!({ "type": "auth", "token": jwt })))
+        .unwrap();
+
+    socket
 }
 
 #[tokio::test(flavor = "multi_thread")]
 async fn test_valid_session() {
-    let start_mock = init_session().await;
+    env::set_var(
+        "APP_ROLES",
+        r#"[{"role":"moderator","attribute":"pbdf.sidn-pbdf.email.email","suffix":"@example.com"}]"#,
+    );
+    let (start_mock, url) = init_session().await;
 
     let sse_mock = mockito::mock("GET", "/session/P9hCuu0hCQtfFndWXgoQ/statusevents")
         .with_status(200)
         .with_header("content-type", "text/event-stream")
         .with_body_from_fn(|w| {
-            w.write(b"data: CONNECTED\n\r")?;
-            w.write(b"data: DONE\n\r")?;
+            w.write_all(b"data: CONNECTED\n\r")?;
+            w.write_all(b"data: DONE\n\r")?;
             Ok(())
         })
         .create();
@@ -67,6 +151,7 @@ async fn test_valid_session() {
       "attributes": {
         "pbdf.pbdf.idin.initials": "Foo",
         "pbdf.pbdf.idin.familyname": "Bar",
+        "pbdf.sidn-pbdf.email.email": "foo@Example.com",
       },
       "exp": Utc::now().timestamp() + 300,
       "iat": Utc::now().timestamp(),
@@ -83,7 +168,6 @@ async fn test_valid_session() {
         .with_body(jwt)
         .create();
 
-    let url = format!("ws://{}", env::var("WS_HOST").unwrap());
     let (mut socket, _) = connect(url).expect("Failed to connect");
 
     socket.write_message("start".into()).unwrap();
@@ -101,10 +185,38 @@ async fn test_valid_session() {
 
     let jwt_action: Action =
         serde_json::from_str(socket.read_message().unwrap().to_string().as_str()).unwrap();
-    let key = config::get("APP_JWT_KEY");
-    let decode_result = decode::<SessionJwt>(key, jwt_action.payload).unwrap();
+    let decode_result = SessionJwt::from_jwt(jwt_action.payload).unwrap();
 
     assert_eq!(decode_result.sub, "Foo Bar");
+    assert_eq!(decode_result.roles, vec![Role::Moderator]);
+
+    // the disclosed credentials are listed without their values
+    let credentials: Vec<(&str, &str, Vec<String>)> = decode_result
+        .badges
+        .iter()
+        .map(|b| {
+            (
+                b.credential.as_str(),
+                b.issuer.as_str(),
+                b.attributes.clone(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        credentials,
+        vec![
+            (
+                "pbdf.pbdf.idin",
+                "pbdf.pbdf",
+                vec!["familyname".to_string(), "initials".to_string()]
+            ),
+            (
+                "pbdf.sidn-pbdf.email",
+                "pbdf.sidn-pbdf",
+                vec!["email".to_string()]
+            ),
+        ]
+    );
 
     start_mock.assert();
     sse_mock.assert();
@@ -113,14 +225,14 @@ async fn test_valid_session() {
 
 #[tokio::test(flavor = "multi_thread")]
 async fn test_invalid_session() {
-    let start_mock = init_session().await;
+    let (start_mock, url) = init_session().await;
 
     let sse_mock = mockito::mock("GET", "/session/P9hCuu0hCQtfFndWXgoQ/statusevents")
         .with_status(200)
         .with_header("content-type", "text/event-stream")
         .with_body_from_fn(|w| {
-            w.write(b"data: CONNECTED\n\r")?;
-            w.write(b"data: DONE\n\r")?;
+            w.write_all(b"data: CONNECTED\n\r")?;
+            w.write_all(b"data: DONE\n\r")?;
             Ok(())
         })
         .create();
@@ -144,7 +256,6 @@ async fn test_invalid_session() {
         .with_body(jwt)
         .create();
 
-    let url = format!("ws://{}", env::var("WS_HOST").unwrap());
     let (mut socket, _) = connect(url).expect("Failed to connect");
 
     socket.write_message("start".into()).unwrap();
@@ -171,19 +282,18 @@ async fn test_invalid_session() {
 
 #[tokio::test(flavor = "multi_thread")]
 async fn test_cancel() {
-    let start_mock = init_session().await;
+    let (start_mock, url) = init_session().await;
 
     let sse_mock = mockito::mock("GET", "/session/P9hCuu0hCQtfFndWXgoQ/statusevents")
         .with_status(200)
         .with_header("content-type", "text/event-stream")
         .with_body_from_fn(|w| {
-            w.write(b"data: CONNECTED\n\r")?;
-            w.write(b"data: CANCELLED\n\r")?;
+            w.write_all(b"data: CONNECTED\n\r")?;
+            w.write_all(b"data: CANCELLED\n\r")?;
             Ok(())
         })
         .create();
 
-    let url = format!("ws://{}", env::var("WS_HOST").unwrap());
     let (mut socket, _) = connect(url).expect("Failed to connect");
 
     socket.write_message("start".into()).unwrap();
@@ -204,53 +314,2498 @@ async fn test_cancel() {
 }
 
 #[tokio::test(flavor = "multi_thread")]
-async fn test_chat() {
+async fn test_start_timeout() {
+    env::set_var("WS_START_TIMEOUT", "1");
+    let (_start_mock, url) = init_session().await;
+
+    // clients that never start a session are disconnected
+    let (mut socket, _) = connect(url).expect("Failed to connect");
+    assert!(socket.read_message().unwrap().is_close());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_auth_timeout() {
     dotenv().ok();
+    let state = ChatState {
+        config: ChatConfig {
+            auth_timeout: Duration::from_secs(1),
+            ..ChatConfig::from_env()
+        },
+        ..ChatState::default()
+    };
+    let url = init_chat_with(state).await;
 
-    tokio::spawn(async {
-        let chat_host = config::get("CHAT_WS_HOST");
-        let chat_listener = TcpListener::bind(chat_host).await.expect("Failed to bind");
-        let state = PeerMap::new(Mutex::new(HashMap::new()));
+    // clients that never send their session token are disconnected
+    let (mut socket, _) = connect(url).expect("Failed to connect");
+    assert!(socket.read_message().unwrap().is_close());
+}
 
-        while let Ok((stream, addr)) = chat_listener.accept().await {
+#[tokio::test(flavor = "multi_thread")]
+async fn test_shutdown() {
+    let (start_mock, _) = init_session().await;
+    let sse_mock = mockito::mock("GET", "/session/P9hCuu0hCQtfFndWXgoQ/statusevents")
+        .with_status(200)
+        .with_header("content-type", "text/event-stream")
+        .with_body("data: CONNECTED\n\r")
+        .create();
+    let stop_mock = mockito::mock("DELETE", "/session/P9hCuu0hCQtfFndWXgoQ")
+        .with_status(204)
+        .create();
+
+    // a server with a single authentication and chat connection
+    let shutdown = Shutdown::new();
+    let auth_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let auth_url = format!("ws://{}", auth_listener.local_addr().unwrap());
+    let auth_shutdown = shutdown.signal();
+    tokio::spawn(async move {
+        let (stream, _) = auth_listener.accept().await.unwrap();
+        auth_socket::handle_auth_connection(stream, auth_shutdown).await;
+    });
+
+    let chat_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let chat_url = format!("ws://{}", chat_listener.local_addr().unwrap());
+    let chat_shutdown = shutdown.signal();
+    tokio::spawn(async move {
+        let state = ChatState::default();
+        for _ in 0..2 {
+            let (stream, addr) = chat_listener.accept().await.unwrap();
             tokio::spawn(chat_socket::handle_chat_connection(
                 state.clone(),
                 stream,
                 addr,
+                chat_shutdown.clone(),
             ));
         }
     });
 
-    let app_key = config::get("APP_JWT_KEY");
+    let (mut auth_socket, _) = connect(auth_url).expect("Failed to connect");
+    auth_socket.write_message("start".into()).unwrap();
+    auth_socket.read_message().unwrap();
+    assert_eq!(
+        auth_socket.read_message().unwrap().to_string(),
+        r#"{"action":"status","payload":"CONNECTED"}"#
+    );
+
+    let jwt = SessionJwt::new("Foo".to_string()).as_jwt().unwrap();
+    let mut chat_socket = connect_chat(&chat_url, &jwt);
+    chat_socket.read_message().unwrap();
+
+    // a chat connection that did not authenticate yet
+    let (mut waiting_socket, _) = connect(&chat_url).expect("Failed to connect");
+
+    // clients are asked to reconnect and the IRMA session is cancelled
+    let drained = tokio::spawn(shutdown.drain(Duration::from_secs(5)));
+
+    for socket in [&mut auth_socket, &mut chat_socket, &mut waiting_socket] {
+        match socket.read_message().unwrap() {
+            Message::Close(Some(frame)) => {
+                assert_eq!(frame.code, CloseCode::Away);
+                assert_eq!(frame.reason, "reconnect");
+            }
+            message => panic!("Expected a close frame, got {:?}", message),
+        }
+        while socket.read_message().is_ok() {}
+    }
+
+    // the server waits until all connections are closed
+    assert!(drained.await.unwrap());
+
+    start_mock.assert();
+    sse_mock.assert();
+    stop_mock.assert();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_disclose_session() {
+    gate_rooms();
+    let (start_mock, url) = init_session().await;
+    let session = SessionJwt::new("Foo Bar".to_string());
+    let token = session.as_jwt().unwrap();
+
+    // only attributes of gated rooms can be added to a session
+    let (mut socket, _) = connect(&url).expect("Failed to connect");
+    let request = json!({
+        "action": "disclose",
+        "token": token,
+        "attributes": [{ "attribute": "pbdf.pbdf.idin.familyname" }],
+    });
+    socket.write_message(request.to_string().into()).unwrap();
+    assert_eq!(
+        socket.read_message().unwrap().to_string(),
+        r#"{"action":"error","payload":"Invalid request"}"#
+    );
+
+    let sse_mock = mockito::mock("GET", "/session/P9hCuu0hCQtfFndWXgoQ/statusevents")
+        .with_status(200)
+        .with_header("content-type", "text/event-stream")
+        .with_body_from_fn(|w| {
+            w.write_all(b"data: DONE\n\r")?;
+            Ok(())
+        })
+        .create();
+
+    // the identifying attributes show the proof comes from the owner of the session
     let claim = json!({
+      "attributes": {
+        "pbdf.gemeente.personalData.fullname": "Foo Bar",
+        "pbdf.gemeente.personalData.over18": "Yes",
+      },
       "exp": Utc::now().timestamp() + 300,
-      "sub": "Foo Bar"
+      "iat": Utc::now().timestamp(),
+      "iss": "irmaserver",
+      "status": "VALID",
+      "sub": "disclosure_result"
     });
-    let jwt = encode(app_key, claim).unwrap();
+    let app_priv_key_file = config::get("IRMA_SERVER_JWT_PRIVKEY_FILE");
+    let jwt = encode_rsa(app_priv_key_file, claim).await.unwrap();
+
+    let proof_mock = mockito::mock("GET", "/session/P9hCuu0hCQtfFndWXgoQ/getproof")
+        .with_status(200)
+        .with_body(jwt)
+        .create();
 
-    let url = format!("ws://{}", env::var("CHAT_WS_HOST").unwrap());
     let (mut socket, _) = connect(url).expect("Failed to connect");
+    let request = json!({
+        "action": "disclose",
+        "token": token,
+        "attributes": [{ "attribute": "pbdf.gemeente.personalData.over18", "value": "Yes" }],
+    });
+    socket.write_message(request.to_string().into()).unwrap();
 
-    socket.write_message(jwt.into()).unwrap();
-    socket.write_message("Hello World!".into()).unwrap();
+    assert_eq!(read_json(&mut socket)["action"], "qr");
+    assert_eq!(
+        socket.read_message().unwrap().to_string(),
+        r#"{"action":"status","payload":"DONE"}"#
+    );
 
-    let time = Utc::now().timestamp();
+    // the disclosed attribute is added to the existing session
+    let jwt_action: Action =
+        serde_json::from_str(socket.read_message().unwrap().to_string().as_str()).unwrap();
+    let disclosed = SessionJwt::from_jwt(jwt_action.payload).unwrap();
+
+    assert_eq!(disclosed.sub, "Foo Bar");
+    assert_eq!(disclosed.jti, session.jti);
+    assert_eq!(disclosed.exp, session.exp);
+    assert_eq!(
+        disclosed
+            .attributes
+            .get("pbdf.gemeente.personalData.over18"),
+        Some(&"Yes".to_string())
+    );
+
+    start_mock.assert();
+    sse_mock.assert();
+    proof_mock.assert();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_disclose_other_identity() {
+    gate_rooms();
+    let (start_mock, url) = init_session().await;
+    let token = SessionJwt::new("Foo Bar".to_string()).as_jwt().unwrap();
+
+    let sse_mock = mockito::mock("GET", "/session/P9hCuu0hCQtfFndWXgoQ/statusevents")
+        .with_status(200)
+        .with_header("content-type", "text/event-stream")
+        .with_body_from_fn(|w| {
+            w.write_all(b"data: DONE\n\r")?;
+            Ok(())
+        })
+        .create();
+
+    // someone else has the requested attribute
+    let claim = json!({
+      "attributes": {
+        "pbdf.gemeente.personalData.fullname": "Someone Else",
+        "pbdf.gemeente.personalData.over18": "Yes",
+      },
+      "exp": Utc::now().timestamp() + 300,
+      "iat": Utc::now().timestamp(),
+      "iss": "irmaserver",
+      "status": "VALID",
+      "sub": "disclosure_result"
+    });
+    let app_priv_key_file = config::get("IRMA_SERVER_JWT_PRIVKEY_FILE");
+    let jwt = encode_rsa(app_priv_key_file, claim).await.unwrap();
+
+    let proof_mock = mockito::mock("GET", "/session/P9hCuu0hCQtfFndWXgoQ/getproof")
+        .with_status(200)
+        .with_body(jwt)
+        .create();
+
+    let (mut socket, _) = connect(url).expect("Failed to connect");
+    let request = json!({
+        "action": "disclose",
+        "token": token,
+        "attributes": [{ "attribute": "pbdf.gemeente.personalData.over18", "value": "Yes" }],
+    });
+    socket.write_message(request.to_string().into()).unwrap();
 
+    assert_eq!(read_json(&mut socket)["action"], "qr");
     assert_eq!(
         socket.read_message().unwrap().to_string(),
-        format!(
-            "{{\"user\":\"Foo Bar\",\"time\":{},\"its_me\":true,\"msg\":null}}",
-            time
-        )
+        r#"{"action":"status","payload":"DONE"}"#
+    );
+
+    // the attributes are not added to the session of Foo Bar
+    assert_eq!(
+        socket.read_message().unwrap().to_string(),
+        r#"{"action":"error","payload":"Disclosure does not match the session"}"#
+    );
+
+    start_mock.assert();
+    sse_mock.assert();
+    proof_mock.assert();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pseudonymous_session() {
+    gate_rooms();
+    env::set_var(
+        "IRMA_PSEUDONYM_ATTRIBUTES",
+        r#"[["pbdf.sidn-pbdf.email.email"]]"#,
+    );
+    env::set_var("APP_PSEUDONYM_KEY", "pseudonymsecret");
+    let (start_mock, url) = init_session().await;
+
+    // picked names are validated before starting an IRMA session
+    let (mut socket, _) = connect(&url).expect("Failed to connect");
+    let request = json!({ "action": "pseudonymous", "pseudonym": "Foo Bar" });
+    socket.write_message(request.to_string().into()).unwrap();
+    assert_eq!(
+        socket.read_message().unwrap().to_string(),
+        r#"{"action":"error","payload":"Invalid request"}"#
+    );
+
+    let sse_mock = mockito::mock("GET", "/session/P9hCuu0hCQtfFndWXgoQ/statusevents")
+        .with_status(200)
+        .with_header("content-type", "text/event-stream")
+        .with_body_from_fn(|w| {
+            w.write_all(b"data: DONE\n\r")?;
+            Ok(())
+        })
+        .create();
+
+    let claim = json!({
+      "attributes": {
+        "pbdf.sidn-pbdf.email.email": "foo@example.com",
+        "pbdf.gemeente.personalData.over18": "Yes",
+      },
+      "exp": Utc::now().timestamp() + 300,
+      "iat": Utc::now().timestamp(),
+      "iss": "irmaserver",
+      "status": "VALID",
+      "sub": "disclosure_result"
+    });
+    let app_priv_key_file = config::get("IRMA_SERVER_JWT_PRIVKEY_FILE");
+    let jwt = encode_rsa(app_priv_key_file, claim).await.unwrap();
+
+    let proof_mock = mockito::mock("GET", "/session/P9hCuu0hCQtfFndWXgoQ/getproof")
+        .with_status(200)
+        .with_body(jwt)
+        .create();
+
+    let (mut socket, _) = connect(url).expect("Failed to connect");
+    let request = json!({ "action": "pseudonymous", "pseudonym": "night-owl" });
+    socket.write_message(request.to_string().into()).unwrap();
+
+    assert_eq!(read_json(&mut socket)["action"], "qr");
+    assert_eq!(
+        socket.read_message().unwrap().to_string(),
+        r#"{"action":"status","payload":"DONE"}"#
+    );
+
+    // the session is tied to a stable id, but does not contain the identifying attributes
+    let jwt_action: Action =
+        serde_json::from_str(socket.read_message().unwrap().to_string().as_str()).unwrap();
+    assert!(!jwt_action.payload.contains("example.com"));
+    let session = SessionJwt::from_jwt(jwt_action.payload).unwrap();
+
+    let (name, id) = session.sub.split_once('#').unwrap();
+    assert_eq!(name, "night-owl");
+    assert_eq!(id.len(), 12);
+    assert!(!serde_json::to_string(&session)
+        .unwrap()
+        .contains("example.com"));
+    assert_eq!(
+        session.attributes.get("pbdf.gemeente.personalData.over18"),
+        Some(&"Yes".to_string())
     );
 
+    start_mock.assert();
+    sse_mock.assert();
+    proof_mock.assert();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_chat() {
+    let url = init_chat().await;
+    let jwt = SessionJwt::new("Foo Bar".to_string()).as_jwt().unwrap();
+
+    let mut socket = connect_chat(&url, &jwt);
+    socket
+        .write_message(chat_request(
+            json!({ "type": "send", "text": "Hello World!" }),
+        ))
+        .unwrap();
+
+    let time = Utc::now().timestamp();
+
     assert_eq!(
         socket.read_message().unwrap().to_string(),
+        r#"{"type":"online","room":"general","users":["Foo Bar"]}"#
+    );
+
+    let message = socket.read_message().unwrap().to_string();
+    let id = serde_json::from_str::<Value>(&message).unwrap()["id"].clone();
+    assert!(id.as_i64().unwrap() > Utc::now().timestamp_millis());
+    assert_eq!(
+        message,
         format!(
-            "{{\"user\":\"Foo Bar\",\"time\":{},\"its_me\":true,\"msg\":\"Hello World!\"}}",
-            time
+            "{{\"type\":\"message\",\"id\":{},\"room\":\"general\",\"user\":\"Foo Bar\",\"time\":{},\"its_me\":true,\"msg\":\"Hello World!\"}}",
+            id, time
         )
     );
 
     socket.close(None).unwrap();
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_invalid_requests() {
+    let url = init_chat().await;
+    let jwt = SessionJwt::new("Foo Bar".to_string()).as_jwt().unwrap();
+
+    // the first request should authenticate the client
+    let (mut socket, _) = connect(&url).expect("Failed to connect");
+    socket.write_message(jwt.as_str().into()).unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "invalid_request");
+
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+
+    // invalid frames are answered with an error instead of being broadcast
+    socket.write_message("Hello World!".into()).unwrap();
+    let error = read_json(&mut socket);
+    assert_eq!(error["type"], "error");
+    assert_eq!(error["code"], "invalid_request");
+
+    socket
+        .write_message(json!({ "v": 2, "type": "ping" }).to_string().into())
+        .unwrap();
+    assert_eq!(
+        socket.read_message().unwrap().to_string(),
+        r#"{"type":"error","code":"unsupported_version","message":"Unsupported protocol version: 2"}"#
+    );
+
+    socket
+        .write_message(chat_request(json!({ "type": "shout", "text": "Hello" })))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "invalid_request");
+
+    socket
+        .write_message(chat_request(json!({ "type": "ping" })))
+        .unwrap();
+    assert_eq!(
+        socket.read_message().unwrap().to_string(),
+        r#"{"type":"pong"}"#
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_invalid_chat_token() {
+    let url = init_chat().await;
+    let app_key = config::get("APP_JWT_KEY");
+    let claim = json!({
+      "exp": Utc::now().timestamp() + 300,
+      "sub": "Foo Bar"
+    });
+    let jwt = encode(app_key, claim).unwrap();
+
+    let mut socket = connect_chat(&url, &jwt);
+    let reply = read_json(&mut socket);
+
+    assert_eq!(reply["code"], "authentication");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_foreign_chat_token() {
+    let url = init_chat().await;
+
+    // tokens of other deployments sharing the same secret are rejected
+    let mut jwt = SessionJwt::new("Foo Bar".to_string());
+    jwt.iss = "other-deployment".to_string();
+    let mut socket = connect_chat(&url, &jwt.as_jwt().unwrap());
+    assert!(read_json(&mut socket)["message"]
+        .as_str()
+        .unwrap()
+        .contains("InvalidIssuer"));
+
+    let mut jwt = SessionJwt::new("Foo Bar".to_string());
+    jwt.aud = "other-service".to_string();
+    let mut socket = connect_chat(&url, &jwt.as_jwt().unwrap());
+    assert!(read_json(&mut socket)["message"]
+        .as_str()
+        .unwrap()
+        .contains("InvalidAudience"));
+
+    // tokens are not accepted before they become valid
+    let mut jwt = SessionJwt::new("Foo Bar".to_string());
+    jwt.nbf += 300;
+    let mut socket = connect_chat(&url, &jwt.as_jwt().unwrap());
+    assert!(read_json(&mut socket)["message"]
+        .as_str()
+        .unwrap()
+        .contains("ImmatureSignature"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_logout() {
+    let url = init_chat().await;
+    let jwt = SessionJwt::new("Foo Bar".to_string()).as_jwt().unwrap();
+    let other_jwt = SessionJwt::new("Foo Bar".to_string()).as_jwt().unwrap();
+
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+    let mut other_socket = connect_chat(&url, &other_jwt);
+    other_socket.read_message().unwrap();
+
+    // logging out closes all connections of the subject
+    socket
+        .write_message(chat_request(json!({ "type": "logout" })))
+        .unwrap();
+    for socket in [&mut socket, &mut other_socket].iter_mut() {
+        match socket.read_message().unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(frame.reason, "logout"),
+            msg => panic!("Expected a close frame, got {:?}", msg),
+        }
+    }
+
+    // the revoked token can no longer be used, other tokens of the subject can
+    let mut socket = connect_chat(&url, &jwt);
+    assert_eq!(
+        socket.read_message().unwrap().to_string(),
+        r#"{"type":"error","code":"authentication","message":"Authentication error: RevokedJWT"}"#
+    );
+
+    let mut other_socket = connect_chat(&url, &other_jwt);
+    assert_eq!(read_json(&mut other_socket)["type"], "online");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_revoke_subject() {
+    let url = init_chat().await;
+    let jwt = SessionJwt::new("Foo Bar".to_string()).as_jwt().unwrap();
+    let admin_jwt = SessionJwt::with_roles("Admin".to_string(), vec![Role::Admin])
+        .as_jwt()
+        .unwrap();
+
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+    let mut admin_socket = connect_chat(&url, &admin_jwt);
+    admin_socket.read_message().unwrap();
+    socket.read_message().unwrap();
+
+    // regular users can not revoke sessions
+    socket
+        .write_message(chat_request(
+            json!({ "type": "revoke", "subject": "Admin" }),
+        ))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "forbidden");
+
+    admin_socket
+        .write_message(chat_request(
+            json!({ "type": "revoke", "subject": "Foo Bar" }),
+        ))
+        .unwrap();
+
+    match socket.read_message().unwrap() {
+        Message::Close(Some(frame)) => assert_eq!(frame.reason, "logout"),
+        msg => panic!("Expected a close frame, got {:?}", msg),
+    }
+
+    let mut socket = connect_chat(&url, &jwt);
+    assert_eq!(read_json(&mut socket)["code"], "authentication");
+
+    let presence = read_json(&mut admin_socket);
+    assert_eq!(presence["user"], "Foo Bar");
+    assert_eq!(presence["action"], "leave");
+
+    admin_socket
+        .write_message(chat_request(
+            json!({ "type": "send", "text": "Still here" }),
+        ))
+        .unwrap();
+    assert_eq!(read_json(&mut admin_socket)["msg"], "Still here");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_moderation() {
+    let url = init_chat().await;
+    let jwt = SessionJwt::new("Spammer".to_string()).as_jwt().unwrap();
+    let moderator_jwt = SessionJwt::with_roles("Moderator".to_string(), vec![Role::Moderator])
+        .as_jwt()
+        .unwrap();
+
+    let mut moderator_socket = connect_chat(&url, &moderator_jwt);
+    moderator_socket.read_message().unwrap();
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+    moderator_socket.read_message().unwrap();
+
+    // regular users can not moderate
+    for request in [
+        json!({ "type": "kick", "subject": "Moderator" }),
+        json!({ "type": "mute", "subject": "Moderator", "seconds": 60 }),
+        json!({ "type": "ban", "subject": "Moderator" }),
+        json!({ "type": "remove", "id": 1 }),
+        json!({ "type": "metrics" }),
+    ] {
+        socket.write_message(chat_request(request)).unwrap();
+        assert_eq!(read_json(&mut socket)["code"], "forbidden");
+    }
+
+    // moderators can remove any message
+    socket
+        .write_message(chat_request(json!({ "type": "send", "text": "Spam" })))
+        .unwrap();
+    let id = read_json(&mut socket)["id"].as_i64().unwrap();
+    assert_eq!(read_json(&mut moderator_socket)["msg"], "Spam");
+
+    moderator_socket
+        .write_message(chat_request(json!({ "type": "remove", "id": id })))
+        .unwrap();
+    let deleted = json!({ "type": "delete", "room": "general", "id": id });
+    assert_eq!(read_json(&mut moderator_socket), deleted);
+    assert_eq!(read_json(&mut socket), deleted);
+
+    // muted users are warned and can no longer post
+    moderator_socket
+        .write_message(chat_request(
+            json!({ "type": "mute", "subject": "Spammer", "seconds": 60 }),
+        ))
+        .unwrap();
+    let warning = read_json(&mut socket);
+    assert_eq!(warning["type"], "warning");
+    assert_eq!(warning["code"], "muted");
+
+    socket
+        .write_message(chat_request(json!({ "type": "send", "text": "More spam" })))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "muted");
+
+    // kicked users can connect again
+    moderator_socket
+        .write_message(chat_request(
+            json!({ "type": "kick", "subject": "Spammer" }),
+        ))
+        .unwrap();
+    match socket.read_message().unwrap() {
+        Message::Close(Some(frame)) => assert_eq!(frame.reason, "kicked"),
+        msg => panic!("Expected a close frame, got {:?}", msg),
+    }
+    assert_eq!(read_json(&mut moderator_socket)["action"], "leave");
+
+    let mut socket = connect_chat(&url, &jwt);
+    assert_eq!(read_json(&mut socket)["type"], "online");
+    assert_eq!(read_json(&mut moderator_socket)["action"], "join");
+
+    // banned users can not start new sessions
+    moderator_socket
+        .write_message(chat_request(json!({ "type": "ban", "subject": "Spammer" })))
+        .unwrap();
+    match socket.read_message().unwrap() {
+        Message::Close(Some(frame)) => assert_eq!(frame.reason, "banned"),
+        msg => panic!("Expected a close frame, got {:?}", msg),
+    }
+    assert_eq!(read_json(&mut moderator_socket)["action"], "leave");
+
+    let new_jwt = SessionJwt::new("Spammer".to_string()).as_jwt().unwrap();
+    let mut socket = connect_chat(&url, &new_jwt);
+    assert_eq!(
+        socket.read_message().unwrap().to_string(),
+        r#"{"type":"error","code":"authentication","message":"Authentication error: BannedSubject"}"#
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pseudonymous_ban() {
+    let url = init_chat().await;
+    let jwt = SessionJwt::new("night-owl#3fa2c91b07de".to_string())
+        .as_jwt()
+        .unwrap();
+    let moderator_jwt = SessionJwt::with_roles("Moderator".to_string(), vec![Role::Moderator])
+        .as_jwt()
+        .unwrap();
+
+    let mut moderator_socket = connect_chat(&url, &moderator_jwt);
+    moderator_socket.read_message().unwrap();
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+
+    moderator_socket
+        .write_message(chat_request(
+            json!({ "type": "mute", "subject": "night-owl#3fa2c91b07de", "seconds": 60 }),
+        ))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "muted");
+
+    // picking another pseudonym does not lift the mute
+    let other_name = SessionJwt::new("day-owl#3fa2c91b07de".to_string())
+        .as_jwt()
+        .unwrap();
+    let mut other_socket = connect_chat(&url, &other_name);
+    assert_eq!(read_json(&mut other_socket)["type"], "online");
+    assert_eq!(read_json(&mut socket)["action"], "join");
+
+    other_socket
+        .write_message(chat_request(json!({ "type": "send", "text": "Hoot" })))
+        .unwrap();
+    assert_eq!(read_json(&mut other_socket)["code"], "muted");
+
+    other_socket.close(None).unwrap();
+    assert_eq!(read_json(&mut socket)["action"], "leave");
+
+    moderator_socket
+        .write_message(chat_request(
+            json!({ "type": "ban", "subject": "night-owl#3fa2c91b07de" }),
+        ))
+        .unwrap();
+    match socket.read_message().unwrap() {
+        Message::Close(Some(frame)) => assert_eq!(frame.reason, "banned"),
+        msg => panic!("Expected a close frame, got {:?}", msg),
+    }
+
+    // picking another pseudonym does not lift the ban
+    let other_name = SessionJwt::new("day-owl#3fa2c91b07de".to_string())
+        .as_jwt()
+        .unwrap();
+    let mut socket = connect_chat(&url, &other_name);
+    assert_eq!(read_json(&mut socket)["code"], "authentication");
+
+    let other_user = SessionJwt::new("night-owl#000000000000".to_string())
+        .as_jwt()
+        .unwrap();
+    let mut socket = connect_chat(&url, &other_user);
+    assert_eq!(read_json(&mut socket)["type"], "online");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_ban_all_pseudonyms() {
+    let url = init_chat().await;
+    let jwt = SessionJwt::new("night-owl#3fa2c91b07de".to_string())
+        .as_jwt()
+        .unwrap();
+    let other_name = SessionJwt::new("day-owl#3fa2c91b07de".to_string())
+        .as_jwt()
+        .unwrap();
+    let moderator_jwt = SessionJwt::with_roles("Moderator".to_string(), vec![Role::Moderator])
+        .as_jwt()
+        .unwrap();
+
+    let mut moderator_socket = connect_chat(&url, &moderator_jwt);
+    moderator_socket.read_message().unwrap();
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+    let mut other_socket = connect_chat(&url, &other_name);
+    assert_eq!(read_json(&mut other_socket)["type"], "online");
+    assert_eq!(read_json(&mut socket)["action"], "join");
+
+    // all pseudonyms of a person are warned about a mute
+    moderator_socket
+        .write_message(chat_request(
+            json!({ "type": "mute", "subject": "night-owl#3fa2c91b07de", "seconds": 60 }),
+        ))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "muted");
+    assert_eq!(read_json(&mut other_socket)["code"], "muted");
+
+    // and all of them are disconnected by a ban
+    moderator_socket
+        .write_message(chat_request(
+            json!({ "type": "ban", "subject": "night-owl#3fa2c91b07de" }),
+        ))
+        .unwrap();
+    for socket in [&mut socket, &mut other_socket].iter_mut() {
+        loop {
+            match socket.read_message().unwrap() {
+                Message::Close(Some(frame)) => {
+                    assert_eq!(frame.reason, "banned");
+                    break;
+                }
+                Message::Text(text) => assert!(text.contains("presence"), "{}", text),
+                msg => panic!("Expected a close frame, got {:?}", msg),
+            }
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rooms() {
+    let url = init_chat().await;
+    let jwt = SessionJwt::new("Foo".to_string()).as_jwt().unwrap();
+    let other_jwt = SessionJwt::new("Bar".to_string()).as_jwt().unwrap();
+
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+    let mut other_socket = connect_chat(&url, &other_jwt);
+    other_socket.read_message().unwrap();
+    socket.read_message().unwrap();
+
+    // messages in a room are only sent to its members
+    socket
+        .write_message(chat_request(json!({ "type": "join", "room": "rust" })))
+        .unwrap();
+    assert_eq!(
+        socket.read_message().unwrap().to_string(),
+        r#"{"type":"online","room":"rust","users":["Foo"]}"#
+    );
+
+    socket
+        .write_message(chat_request(
+            json!({ "type": "send", "room": "rust", "text": "Hello Rust!" }),
+        ))
+        .unwrap();
+    socket
+        .write_message(chat_request(
+            json!({ "type": "send", "text": "Hello World!" }),
+        ))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["msg"], "Hello Rust!");
+    assert_eq!(read_json(&mut socket)["msg"], "Hello World!");
+    assert_eq!(read_json(&mut other_socket)["msg"], "Hello World!");
+
+    // non members can not send messages to a room
+    other_socket
+        .write_message(chat_request(
+            json!({ "type": "send", "room": "rust", "text": "Hello Rust!" }),
+        ))
+        .unwrap();
+    assert_eq!(
+        other_socket.read_message().unwrap().to_string(),
+        r#"{"type":"error","code":"not_a_member","message":"Not a member of room: rust"}"#
+    );
+
+    other_socket
+        .write_message(chat_request(json!({ "type": "rooms" })))
+        .unwrap();
+    assert_eq!(
+        other_socket.read_message().unwrap().to_string(),
+        r#"{"type":"rooms","rooms":[{"name":"general","members":2,"joined":true},{"name":"rust","members":1,"joined":false}]}"#
+    );
+
+    // after leaving a room no more messages of that room are received
+    socket
+        .write_message(chat_request(json!({ "type": "leave", "room": "general" })))
+        .unwrap();
+    socket
+        .write_message(chat_request(json!({ "type": "rooms" })))
+        .unwrap();
+    assert_eq!(
+        socket.read_message().unwrap().to_string(),
+        r#"{"type":"rooms","rooms":[{"name":"general","members":1,"joined":false},{"name":"rust","members":1,"joined":true}]}"#
+    );
+
+    other_socket
+        .write_message(chat_request(json!({ "type": "send", "text": "Anyone?" })))
+        .unwrap();
+    socket
+        .write_message(chat_request(
+            json!({ "type": "send", "room": "rust", "text": "Still here" }),
+        ))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["msg"], "Still here");
+
+    socket
+        .write_message(chat_request(json!({ "type": "join", "room": "no spaces" })))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "invalid_room");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_gated_rooms() {
+    gate_rooms();
+    let url = init_chat().await;
+    let over18 = "pbdf.gemeente.personalData.over18";
+
+    let mut minor = SessionJwt::new("Minor".to_string());
+    minor
+        .attributes
+        .insert(over18.to_string(), "No".to_string());
+    let mut adult = SessionJwt::new("Adult".to_string());
+    adult
+        .attributes
+        .insert(over18.to_string(), "Yes".to_string());
+
+    // users that did not disclose the required attributes are told what is missing, their
+    // sockets are kept open so no presence messages are sent to the next socket
+    let mut sockets = Vec::new();
+    for jwt in [SessionJwt::new("Anonymous".to_string()), minor] {
+        let mut socket = connect_chat(&url, &jwt.as_jwt().unwrap());
+        socket.read_message().unwrap();
+        socket
+            .write_message(chat_request(json!({ "type": "join", "room": "adults" })))
+            .unwrap();
+
+        assert_eq!(
+            read_json(&mut socket),
+            json!({
+                "type": "error",
+                "code": "missing_attributes",
+                "message": "Additional attributes are required to join room: adults",
+                "missing": [{ "attribute": over18, "value": "Yes" }],
+            })
+        );
+        sockets.push(socket);
+    }
+
+    let mut socket = connect_chat(&url, &adult.as_jwt().unwrap());
+    socket.read_message().unwrap();
+    socket
+        .write_message(chat_request(json!({ "type": "join", "room": "adults" })))
+        .unwrap();
+    assert_eq!(
+        socket.read_message().unwrap().to_string(),
+        r#"{"type":"online","room":"adults","users":["Adult"]}"#
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_badges() {
+    let url = init_chat().await;
+    let badge = Badge {
+        credential: "pbdf.gemeente.personalData".to_string(),
+        issuer: "pbdf.gemeente".to_string(),
+        attributes: vec!["over18".to_string()],
+        verified: Utc::now().timestamp(),
+    };
+
+    let mut verified = SessionJwt::new("Verified".to_string());
+    verified.badges = vec![badge.clone()];
+    let jwt = SessionJwt::new("Reader".to_string()).as_jwt().unwrap();
+
+    let mut socket = connect_chat(&url, &verified.as_jwt().unwrap());
+    socket.read_message().unwrap();
+    let mut other_socket = connect_chat(&url, &jwt);
+    other_socket.read_message().unwrap();
+    socket.read_message().unwrap();
+
+    // readers see what the sender disclosed, also in the history
+    socket
+        .write_message(chat_request(json!({ "type": "send", "text": "Trust me" })))
+        .unwrap();
+    let expected = json!([badge]);
+    assert_eq!(read_json(&mut socket)["badges"], expected);
+    assert_eq!(read_json(&mut other_socket)["badges"], expected);
+
+    let mut history_socket = connect_chat(&url, &jwt);
+    history_socket.read_message().unwrap();
+    assert_eq!(
+        read_json(&mut history_socket)["messages"][0]["badges"],
+        expected
+    );
+
+    // users without badges send messages without them
+    other_socket
+        .write_message(chat_request(json!({ "type": "send", "text": "Hi" })))
+        .unwrap();
+    assert!(read_json(&mut socket).get("badges").is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_devices() {
+    dotenv().ok();
+    let state = ChatState {
+        config: ChatConfig {
+            max_devices: 2,
+            ..ChatConfig::from_env()
+        },
+        ..ChatState::default()
+    };
+    let url = init_chat_with(state).await;
+    let jwt = SessionJwt::new("Foo".to_string()).as_jwt().unwrap();
+    let other_jwt = SessionJwt::new("Bar".to_string()).as_jwt().unwrap();
+
+    let mut phone_socket = connect_chat(&url, &jwt);
+    phone_socket.read_message().unwrap();
+    let mut laptop_socket = connect_chat(&url, &jwt);
+    laptop_socket.read_message().unwrap();
+    let mut other_socket = connect_chat(&url, &other_jwt);
+    other_socket.read_message().unwrap();
+    phone_socket.read_message().unwrap();
+    laptop_socket.read_message().unwrap();
+
+    // messages are echoed to all devices of the author
+    phone_socket
+        .write_message(chat_request(json!({ "type": "send", "text": "Hello" })))
+        .unwrap();
+    assert_eq!(read_json(&mut phone_socket)["its_me"], true);
+    assert_eq!(read_json(&mut laptop_socket)["its_me"], true);
+    assert_eq!(read_json(&mut other_socket)["its_me"], false);
+
+    // devices of the same user count as a single member
+    laptop_socket
+        .write_message(chat_request(json!({ "type": "rooms" })))
+        .unwrap();
+    assert_eq!(
+        read_json(&mut laptop_socket)["rooms"],
+        json!([{ "name": "general", "members": 2, "joined": true }])
+    );
+
+    // the number of devices per user is limited
+    let mut tablet_socket = connect_chat(&url, &jwt);
+    assert_eq!(read_json(&mut tablet_socket)["code"], "too_many_devices");
+    assert!(tablet_socket.read_message().unwrap().is_close());
+
+    // leaving a room on one device does not leave it on the others
+    phone_socket
+        .write_message(chat_request(json!({ "type": "leave", "room": "general" })))
+        .unwrap();
+    phone_socket
+        .write_message(chat_request(json!({ "type": "ping" })))
+        .unwrap();
+    assert_eq!(read_json(&mut phone_socket)["type"], "pong");
+
+    other_socket
+        .write_message(chat_request(
+            json!({ "type": "send", "text": "Still there?" }),
+        ))
+        .unwrap();
+    assert_eq!(read_json(&mut other_socket)["msg"], "Still there?");
+    assert_eq!(read_json(&mut laptop_socket)["msg"], "Still there?");
+
+    phone_socket
+        .write_message(chat_request(json!({ "type": "ping" })))
+        .unwrap();
+    assert_eq!(read_json(&mut phone_socket)["type"], "pong");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_direct_message() {
+    let url = init_chat().await;
+    let jwt = SessionJwt::new("Foo".to_string()).as_jwt().unwrap();
+    let other_jwt = SessionJwt::new("Foo Bar".to_string()).as_jwt().unwrap();
+
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+    let mut laptop_socket = connect_chat(&url, &jwt);
+    laptop_socket.read_message().unwrap();
+    let mut other_socket = connect_chat(&url, &other_jwt);
+    other_socket.read_message().unwrap();
+    socket.read_message().unwrap();
+    laptop_socket.read_message().unwrap();
+
+    // the message is delivered to the recipient and to all sessions of the sender
+    socket
+        .write_message(chat_request(
+            json!({ "type": "direct", "to": "Foo Bar", "text": "Hello in private" }),
+        ))
+        .unwrap();
+
+    let time = Utc::now().timestamp();
+    let mut ids = Vec::new();
+    for (socket, its_me) in [
+        (&mut other_socket, false),
+        (&mut socket, true),
+        (&mut laptop_socket, true),
+    ]
+    .iter_mut()
+    {
+        let message = socket.read_message().unwrap().to_string();
+        let id = serde_json::from_str::<Value>(&message).unwrap()["id"].clone();
+        assert_eq!(
+            message,
+            format!(
+                "{{\"type\":\"message\",\"id\":{},\"to\":\"Foo Bar\",\"user\":\"Foo\",\"time\":{},\"its_me\":{},\"msg\":\"Hello in private\"}}",
+                id, time, its_me
+            )
+        );
+        ids.push(id);
+    }
+
+    // all copies of a direct message share the same id
+    assert!(ids.iter().all(|id| id == &ids[0]));
+
+    socket
+        .write_message(chat_request(
+            json!({ "type": "direct", "to": "Nobody", "text": "Hello?" }),
+        ))
+        .unwrap();
+    assert_eq!(
+        socket.read_message().unwrap().to_string(),
+        r#"{"type":"error","code":"user_offline","message":"User is not online: Nobody"}"#
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_presence() {
+    let url = init_chat().await;
+    let jwt = SessionJwt::new("Foo".to_string()).as_jwt().unwrap();
+    let other_jwt = SessionJwt::new("Bar".to_string()).as_jwt().unwrap();
+
+    let mut socket = connect_chat(&url, &jwt);
+    assert_eq!(
+        socket.read_message().unwrap().to_string(),
+        r#"{"type":"online","room":"general","users":["Foo"]}"#
+    );
+
+    // a new client receives the users that are online, the others are told it joined
+    let mut other_socket = connect_chat(&url, &other_jwt);
+    assert_eq!(
+        other_socket.read_message().unwrap().to_string(),
+        r#"{"type":"online","room":"general","users":["Bar","Foo"]}"#
+    );
+    let presence = read_json(&mut socket);
+    assert_eq!(presence["type"], "presence");
+    assert_eq!(presence["room"], "general");
+    assert_eq!(presence["user"], "Bar");
+    assert_eq!(presence["action"], "join");
+
+    // more connections of the same user do not change its presence
+    let mut phone_socket = connect_chat(&url, &other_jwt);
+    phone_socket.read_message().unwrap();
+    other_socket.close(None).unwrap();
+    while other_socket.read_message().is_ok() {}
+
+    socket
+        .write_message(chat_request(json!({ "type": "ping" })))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["type"], "pong");
+
+    // the user leaves when its last connection is closed
+    phone_socket.close(None).unwrap();
+    while phone_socket.read_message().is_ok() {}
+
+    let presence = read_json(&mut socket);
+    assert_eq!(presence["user"], "Bar");
+    assert_eq!(presence["action"], "leave");
+}
+
+#[test]
+#[should_panic(expected = "TEST_ZERO_PING_INTERVAL should not be 0")]
+fn test_zero_heartbeat_interval() {
+    // a zero interval can not be used to ping clients
+    env::set_var("TEST_ZERO_PING_INTERVAL", "0");
+    config::get_nonzero_or("TEST_ZERO_PING_INTERVAL", 30u64);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_heartbeat() {
+    dotenv().ok();
+    let state = ChatState {
+        config: ChatConfig {
+            heartbeat: Heartbeat {
+                interval: Duration::from_millis(100),
+                missed_pongs: 2,
+            },
+            ..ChatConfig::from_env()
+        },
+        ..ChatState::default()
+    };
+    let url = init_chat_with(state).await;
+    let jwt = SessionJwt::new("Foo".to_string()).as_jwt().unwrap();
+    let silent_jwt = SessionJwt::new("Bar".to_string()).as_jwt().unwrap();
+
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+    let _silent_socket = connect_chat(&url, &silent_jwt);
+    assert_eq!(read_json(&mut socket)["action"], "join");
+
+    // clients that answer pings stay connected, silent clients are disconnected
+    let mut pings = 0;
+    let presence = loop {
+        match socket.read_message().unwrap() {
+            Message::Ping(_) => pings += 1,
+            message => break serde_json::from_str::<Value>(&message.to_string()).unwrap(),
+        }
+    };
+    assert!(pings > 0);
+    assert_eq!(presence["user"], "Bar");
+    assert_eq!(presence["action"], "leave");
+
+    socket
+        .write_message(chat_request(
+            json!({ "type": "send", "text": "Still here" }),
+        ))
+        .unwrap();
+    let message = loop {
+        match socket.read_message().unwrap() {
+            Message::Ping(_) => continue,
+            message => break serde_json::from_str::<Value>(&message.to_string()).unwrap(),
+        }
+    };
+    assert_eq!(message["msg"], "Still here");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_search() {
+    dotenv().ok();
+    let state = ChatState {
+        config: ChatConfig {
+            history_size: 2,
+            ..ChatConfig::from_env()
+        },
+        ..ChatState::default()
+    };
+    let url = init_chat_with(state).await;
+    let jwt = SessionJwt::new("Foo".to_string()).as_jwt().unwrap();
+    let other_jwt = SessionJwt::new("Bar".to_string()).as_jwt().unwrap();
+
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+    let mut other_socket = connect_chat(&url, &other_jwt);
+    other_socket.read_message().unwrap();
+    socket.read_message().unwrap();
+
+    for (by_other, text) in [
+        (false, "Rust is fun"),
+        (true, "Rusty bikes"),
+        (false, "rust again"),
+    ] {
+        let sender = if by_other {
+            &mut other_socket
+        } else {
+            &mut socket
+        };
+        sender
+            .write_message(chat_request(json!({ "type": "send", "text": text })))
+            .unwrap();
+        read_json(&mut socket);
+        read_json(&mut other_socket);
+    }
+
+    let search = |query: Value| {
+        let mut request = json!({ "type": "search", "room": "general" });
+        request
+            .as_object_mut()
+            .unwrap()
+            .extend(query.as_object().unwrap().clone());
+        chat_request(request)
+    };
+    let texts = |page: &Value| -> Vec<String> {
+        page["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["msg"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    // results are paginated, the newest first
+    socket
+        .write_message(search(json!({ "query": "rust" })))
+        .unwrap();
+    let page = read_json(&mut socket);
+    assert_eq!(page["type"], "search");
+    assert_eq!(texts(&page), vec!["Rusty bikes", "rust again"]);
+
+    socket
+        .write_message(search(json!({ "query": "rust", "before": page["cursor"] })))
+        .unwrap();
+    let page = read_json(&mut socket);
+    assert_eq!(texts(&page), vec!["Rust is fun"]);
+    assert_eq!(page["cursor"], Value::Null);
+
+    // by author
+    socket
+        .write_message(search(json!({ "query": "rust", "user": "Bar" })))
+        .unwrap();
+    assert_eq!(texts(&read_json(&mut socket)), vec!["Rusty bikes"]);
+
+    // edits are searchable right away
+    other_socket
+        .write_message(search(json!({ "query": "fun" })))
+        .unwrap();
+    let page = read_json(&mut other_socket);
+    socket
+        .write_message(chat_request(json!({
+            "type": "edit",
+            "id": page["messages"][0]["id"],
+            "text": "Rust is great"
+        })))
+        .unwrap();
+    read_json(&mut socket);
+    read_json(&mut other_socket);
+    other_socket
+        .write_message(search(json!({ "query": "great" })))
+        .unwrap();
+    assert_eq!(texts(&read_json(&mut other_socket)), vec!["Rust is great"]);
+
+    // only the history of joined rooms can be searched
+    socket
+        .write_message(chat_request(
+            json!({ "type": "search", "room": "rust", "query": "rust" }),
+        ))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "not_a_member");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_mentions() {
+    let url = init_chat().await;
+    let jwt = |sub: &str| SessionJwt::new(sub.to_string()).as_jwt().unwrap();
+    let send = |text: &str| chat_request(json!({ "type": "send", "text": text }));
+
+    // skips the presence of other users
+    let read = |socket: &mut WebSocket<AutoStream>| loop {
+        let response = read_json(socket);
+        if response["type"] != "presence" {
+            break response;
+        }
+    };
+
+    // a user that wrote a message before, but is offline
+    let mut qux = connect_chat(&url, &jwt("Qux"));
+    read(&mut qux);
+    qux.write_message(send("Hello")).unwrap();
+    read(&mut qux);
+    qux.close(None).unwrap();
+    while qux.read_message().is_ok() {}
+
+    let mut sockets = Vec::new();
+    for user in ["Foo", "Bar", "Baz", "owl#aaaaaaaaaaaa", "owl#bbbbbbbbbbbb"].iter() {
+        let mut socket = connect_chat(&url, &jwt(user));
+        assert_eq!(read(&mut socket)["type"], "online");
+        assert_eq!(read(&mut socket)["type"], "history");
+        sockets.push(socket);
+    }
+    let (foo, others) = sockets.split_first_mut().unwrap();
+    let (bar, others) = others.split_first_mut().unwrap();
+    let (baz, owls) = others.split_first_mut().unwrap();
+
+    baz.write_message(chat_request(json!({ "type": "leave", "room": "general" })))
+        .unwrap();
+    baz.write_message(chat_request(json!({ "type": "ping" })))
+        .unwrap();
+    assert_eq!(read(baz)["type"], "pong");
+
+    // mentioned users are notified, also when they are not in the room
+    foo.write_message(send("Hi @bar, @Baz and @qux!")).unwrap();
+    let message = read(foo);
+    assert_eq!(read(bar)["id"], message["id"]);
+    let mention = read(bar);
+    assert_eq!(mention["type"], "mention");
+    assert_eq!(mention["id"], message["id"]);
+    assert_eq!(mention["user"], "Foo");
+    let mention = read(baz);
+    assert_eq!(mention["type"], "mention");
+    assert_eq!(mention["msg"], "Hi @bar, @Baz and @qux!");
+
+    // names of multiple users should be disambiguated
+    foo.write_message(send("@owl hi")).unwrap();
+    read(foo);
+    let warning = read(foo);
+    assert_eq!(warning["type"], "warning");
+    assert_eq!(warning["code"], "ambiguous_mention");
+    assert_eq!(
+        warning["candidates"],
+        json!(["owl#aaaaaaaaaaaa", "owl#bbbbbbbbbbbb"])
+    );
+
+    foo.write_message(send("@owl#bbbbbbbbbbbb hi")).unwrap();
+    let owl_message = read(foo);
+    for owl in owls.iter_mut() {
+        for _ in 0..3 {
+            assert_eq!(read(owl)["type"], "message");
+        }
+    }
+    let mention = read(&mut owls[1]);
+    assert_eq!(mention["type"], "mention");
+    assert_eq!(mention["id"], owl_message["id"]);
+    owls[0]
+        .write_message(chat_request(json!({ "type": "ping" })))
+        .unwrap();
+    assert_eq!(read(&mut owls[0])["type"], "pong");
+
+    // unread mentions are delivered when connecting, until they are acknowledged
+    let mut qux = connect_chat(&url, &jwt("Qux"));
+    read(&mut qux);
+    read(&mut qux);
+    let mention = read(&mut qux);
+    assert_eq!(mention["type"], "mention");
+    assert_eq!(mention["id"], message["id"]);
+
+    qux.write_message(chat_request(json!({ "type": "ack", "id": mention["id"] })))
+        .unwrap();
+    qux.close(None).unwrap();
+    while qux.read_message().is_ok() {}
+
+    let mut qux = connect_chat(&url, &jwt("Qux"));
+    read(&mut qux);
+    read(&mut qux);
+    qux.write_message(chat_request(json!({ "type": "ping" })))
+        .unwrap();
+    assert_eq!(read(&mut qux)["type"], "pong");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_unread_mentions() {
+    let state = ChatState::default();
+    let store = state.store.clone();
+    let url = init_chat_with(state).await;
+    let jwt = SessionJwt::new("Qux".to_string()).as_jwt().unwrap();
+
+    // more unread mentions than requests allowed at once
+    for id in 1..=15 {
+        let message = StoredMessage {
+            id,
+            room: "general".to_string(),
+            user: "Foo".to_string(),
+            time: Utc::now().timestamp(),
+            msg: format!("Hi @Qux, number {}", id),
+            edited: None,
+            reply_to: None,
+            reactions: Vec::new(),
+            badges: Vec::new(),
+            attachment: None,
+        };
+        store.insert(&message).unwrap();
+        store.add_mention("Qux", id).unwrap();
+    }
+
+    let mut socket = connect_chat(&url, &jwt);
+    assert_eq!(read_json(&mut socket)["type"], "online");
+    assert_eq!(read_json(&mut socket)["type"], "history");
+    let mut ids = Vec::new();
+    for _ in 0..15 {
+        let mention = read_json(&mut socket);
+        assert_eq!(mention["type"], "mention");
+        ids.push(mention["id"].clone());
+    }
+
+    // all mentions are acknowledged with a single request
+    socket
+        .write_message(chat_request(json!({ "type": "ack", "ids": ids })))
+        .unwrap();
+    socket.close(None).unwrap();
+    while socket.read_message().is_ok() {}
+
+    assert!(store.unread_mentions("Qux").unwrap().is_empty());
+
+    let mut socket = connect_chat(&url, &jwt);
+    assert_eq!(read_json(&mut socket)["type"], "online");
+    assert_eq!(read_json(&mut socket)["type"], "history");
+    socket
+        .write_message(chat_request(json!({ "type": "ping" })))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["type"], "pong");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_message_content() {
+    dotenv().ok();
+    let state = ChatState {
+        config: ChatConfig {
+            max_message_length: 10,
+            ..ChatConfig::from_env()
+        },
+        ..ChatState::default()
+    };
+    let url = init_chat_with(state).await;
+    let jwt = SessionJwt::new("Foo".to_string()).as_jwt().unwrap();
+
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+
+    let send = |text: &str| chat_request(json!({ "type": "send", "text": text }));
+
+    // messages without visible content are rejected
+    for text in [
+        "",
+        "  \n ",
+        "\u{202e}\u{7}",
+        "\u{200b}",
+        "\u{2060}\u{feff}\u{200e}\u{200f}\u{61c}",
+        " \u{200d} ",
+    ]
+    .iter()
+    {
+        socket.write_message(send(text)).unwrap();
+        let error = read_json(&mut socket);
+        assert_eq!(error["code"], "invalid_content");
+        assert_eq!(error["reason"], "empty");
+    }
+
+    // as are long messages
+    socket.write_message(send("Hello world")).unwrap();
+    let error = read_json(&mut socket);
+    assert_eq!(error["code"], "invalid_content");
+    assert_eq!(error["reason"], "too_long");
+    assert_eq!(error["max_length"], 10);
+
+    // text is normalized, control characters and bidi overrides are removed
+    socket
+        .write_message(send(" cafe\u{301}\u{202e}\u{0}!\u{2066} "))
+        .unwrap();
+    let message = read_json(&mut socket);
+    assert_eq!(message["msg"], "caf\u{e9}!");
+
+    // as are invisible characters, joiners are kept in emoji
+    socket
+        .write_message(send("\u{feff}\u{1f469}\u{200d}\u{1f467}\u{200b}!\u{200d}"))
+        .unwrap();
+    let emoji = read_json(&mut socket);
+    assert_eq!(emoji["msg"], "\u{1f469}\u{200d}\u{1f467}!");
+
+    // edits are validated as well
+    socket
+        .write_message(chat_request(
+            json!({ "type": "edit", "id": message["id"], "text": "\u{1b}" }),
+        ))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["reason"], "empty");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_attachments() {
+    dotenv().ok();
+    let state = ChatState {
+        config: ChatConfig {
+            attachments: AttachmentLimits {
+                max_size: 100,
+                types: vec!["image/png".to_string()],
+            },
+            ..ChatConfig::from_env()
+        },
+        ..ChatState::default()
+    };
+    let url = init_chat_with(state).await;
+    let jwt = SessionJwt::new("Foo".to_string()).as_jwt().unwrap();
+    let other_jwt = SessionJwt::new("Bar".to_string()).as_jwt().unwrap();
+
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+    let mut other_socket = connect_chat(&url, &other_jwt);
+    other_socket.read_message().unwrap();
+    socket.read_message().unwrap();
+
+    let upload = |mime: &str, size: usize| {
+        chat_request(json!({
+            "type": "upload",
+            "name": "cat.png",
+            "mime": mime,
+            "size": size,
+            "text": "Look"
+        }))
+    };
+
+    // binary frames should belong to an announced attachment
+    socket
+        .write_message(Message::Binary(vec![1, 2, 3]))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "invalid_request");
+
+    // attachments are limited in type and size
+    socket.write_message(upload("application/pdf", 10)).unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "invalid_attachment");
+    socket.write_message(upload("image/png", 101)).unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "invalid_attachment");
+
+    // file names can not hide their extension with formatting characters
+    socket
+        .write_message(chat_request(json!({
+            "type": "upload",
+            "name": "invoice\u{202e}gnp.exe",
+            "mime": "image/png",
+            "size": 10,
+            "text": "Invoice"
+        })))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "invalid_attachment");
+
+    // and should match their type
+    socket.write_message(upload("image/png", 4)).unwrap();
+    socket
+        .write_message(Message::Binary(b"MZ!!".to_vec()))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "invalid_attachment");
+
+    // attachments are uploaded in chunks and sent to the room
+    let png = b"\x89PNG\r\n\x1a\nnot really an image".to_vec();
+    socket
+        .write_message(upload("image/png", png.len()))
+        .unwrap();
+    socket
+        .write_message(Message::Binary(png[..10].to_vec()))
+        .unwrap();
+    socket
+        .write_message(Message::Binary(png[10..].to_vec()))
+        .unwrap();
+
+    let message = read_json(&mut socket);
+    assert_eq!(
+        read_json(&mut other_socket),
+        Value::Object({
+            let mut other = message.as_object().unwrap().clone();
+            other.insert("its_me".to_string(), json!(false));
+            other
+        })
+    );
+    assert_eq!(message["msg"], "Look");
+    assert_eq!(
+        message["attachment"],
+        json!({
+            "name": "cat.png",
+            "mime": "image/png",
+            "size": png.len(),
+            "hash": attachments::hash(&png),
+        })
+    );
+
+    // members of the room can download the content
+    other_socket
+        .write_message(chat_request(
+            json!({ "type": "download", "id": message["id"] }),
+        ))
+        .unwrap();
+    let download = read_json(&mut other_socket);
+    assert_eq!(download["type"], "download");
+    assert_eq!(download["chunks"], 1);
+    assert_eq!(other_socket.read_message().unwrap(), Message::Binary(png));
+
+    // binary frames outside of an upload count as requests
+    let limited = (0..20).any(|_| {
+        socket
+            .write_message(Message::Binary(vec![1, 2, 3]))
+            .unwrap();
+        let response = read_json(&mut socket);
+        if response["type"] == "warning" {
+            assert_eq!(response["code"], "rate_limited");
+            return true;
+        }
+        assert_eq!(response["code"], "invalid_request");
+        false
+    });
+    assert!(limited);
+}
+
+// a message store of which every call fails, except for the reads at startup
+struct FailingStore;
+
+fn store_failure<T>() -> Result<T, Error> {
+    Err(Error::Io(io::Error::other("disk full")))
+}
+
+impl MessageStore for FailingStore {
+    fn insert(&self, _: &StoredMessage) -> Result<(), Error> {
+        store_failure()
+    }
+
+    fn get(&self, _: i64) -> Result<Option<StoredMessage>, Error> {
+        store_failure()
+    }
+
+    fn last_id(&self) -> Result<Option<i64>, Error> {
+        Ok(None)
+    }
+
+    fn edit(&self, _: i64, _: &str, _: i64) -> Result<(), Error> {
+        store_failure()
+    }
+
+    fn delete(&self, _: i64) -> Result<(), Error> {
+        store_failure()
+    }
+
+    fn react(&self, _: i64, _: &str, _: &str, _: bool) -> Result<Option<StoredMessage>, Error> {
+        store_failure()
+    }
+
+    fn thread(&self, _: i64) -> Result<Vec<StoredMessage>, Error> {
+        store_failure()
+    }
+
+    fn history(&self, _: &str, _: Option<i64>, _: usize) -> Result<Vec<StoredMessage>, Error> {
+        store_failure()
+    }
+
+    fn search(
+        &self,
+        _: &str,
+        _: &SearchQuery,
+        _: Option<i64>,
+        _: usize,
+    ) -> Result<Vec<StoredMessage>, Error> {
+        store_failure()
+    }
+
+    fn authors(&self) -> Result<Vec<String>, Error> {
+        Ok(Vec::new())
+    }
+
+    fn add_mention(&self, _: &str, _: i64) -> Result<(), Error> {
+        store_failure()
+    }
+
+    fn unread_mentions(&self, _: &str) -> Result<Vec<StoredMessage>, Error> {
+        store_failure()
+    }
+
+    fn read_mention(&self, _: &str, _: i64) -> Result<(), Error> {
+        store_failure()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_store_failures() {
+    dotenv().ok();
+    let state = ChatState::new(
+        Arc::new(FailingStore),
+        Arc::new(MemoryAttachments::default()),
+        ChatConfig::from_env(),
+    );
+    let url = init_chat_with(state).await;
+    let jwt = SessionJwt::new("Foo".to_string()).as_jwt().unwrap();
+
+    // the history of the default room could not be loaded
+    let mut socket = connect_chat(&url, &jwt);
+    assert_eq!(read_json(&mut socket)["type"], "online");
+    assert_eq!(read_json(&mut socket)["code"], "storage");
+
+    // requests that need the store are answered with an error instead of being dropped
+    let requests = vec![
+        json!({ "type": "history", "room": "general" }),
+        json!({ "type": "search", "room": "general", "query": "hello" }),
+        json!({ "type": "thread", "id": 1 }),
+        json!({ "type": "edit", "id": 1, "text": "Hello" }),
+        json!({ "type": "react", "id": 1, "emoji": "👍", "add": true }),
+        json!({ "type": "ack", "ids": [1] }),
+    ];
+    for request in requests {
+        socket.write_message(chat_request(request)).unwrap();
+        assert_eq!(read_json(&mut socket)["code"], "storage");
+    }
+
+    // and messages that could not be stored are not sent to the room
+    socket
+        .write_message(chat_request(json!({ "type": "send", "text": "Hello" })))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "storage");
+    socket
+        .write_message(chat_request(json!({ "type": "ping" })))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["type"], "pong");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_typing() {
+    dotenv().ok();
+    let state = ChatState {
+        config: ChatConfig {
+            typing_throttle: Duration::from_millis(200),
+            typing_timeout: Duration::from_millis(500),
+            ..ChatConfig::from_env()
+        },
+        ..ChatState::default()
+    };
+    let url = init_chat_with(state).await;
+    let jwt = SessionJwt::new("Foo".to_string()).as_jwt().unwrap();
+    let other_jwt = SessionJwt::new("Bar".to_string()).as_jwt().unwrap();
+
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+    let mut other_socket = connect_chat(&url, &other_jwt);
+    other_socket.read_message().unwrap();
+    socket.read_message().unwrap();
+
+    let typing = |typing: bool| {
+        chat_request(json!({ "type": "typing", "room": "general", "typing": typing }))
+    };
+
+    // typing indicators are relayed to the other members of the room
+    socket.write_message(typing(true)).unwrap();
+    assert_eq!(
+        other_socket.read_message().unwrap().to_string(),
+        r#"{"type":"typing","room":"general","user":"Foo","typing":true}"#
+    );
+
+    // bursts of updates are throttled
+    socket.write_message(typing(true)).unwrap();
+    socket.write_message(typing(false)).unwrap();
+    socket.write_message(typing(true)).unwrap();
+    socket.write_message(typing(false)).unwrap();
+    assert_eq!(read_json(&mut other_socket)["typing"], false);
+
+    other_socket
+        .write_message(chat_request(json!({ "type": "ping" })))
+        .unwrap();
+    assert_eq!(read_json(&mut other_socket)["type"], "pong");
+
+    // the server stops the indicator when it is not refreshed in time
+    thread::sleep(Duration::from_millis(200));
+    socket.write_message(typing(true)).unwrap();
+    assert_eq!(read_json(&mut other_socket)["typing"], true);
+
+    let started = Instant::now();
+    assert_eq!(read_json(&mut other_socket)["typing"], false);
+    assert!(started.elapsed() >= Duration::from_millis(400));
+
+    // typing indicators are not part of the history
+    socket
+        .write_message(chat_request(
+            json!({ "type": "history", "room": "general" }),
+        ))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["messages"], json!([]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_edit_messages() {
+    dotenv().ok();
+    let state = ChatState::default();
+    let store = state.store.clone();
+    let url = init_chat_with(state).await;
+    let jwt = SessionJwt::new("Foo".to_string()).as_jwt().unwrap();
+    let other_jwt = SessionJwt::new("Bar".to_string()).as_jwt().unwrap();
+
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+    let mut other_socket = connect_chat(&url, &other_jwt);
+    other_socket.read_message().unwrap();
+    socket.read_message().unwrap();
+
+    socket
+        .write_message(chat_request(json!({ "type": "send", "text": "Helo" })))
+        .unwrap();
+    let id = read_json(&mut socket)["id"].as_i64().unwrap();
+    other_socket.read_message().unwrap();
+
+    // only the author can change a message
+    other_socket
+        .write_message(chat_request(json!({ "type": "delete", "id": id })))
+        .unwrap();
+    assert_eq!(read_json(&mut other_socket)["code"], "forbidden");
+
+    // edits are broadcast to the room and stored
+    socket
+        .write_message(chat_request(
+            json!({ "type": "edit", "id": id, "text": "Hello" }),
+        ))
+        .unwrap();
+    for socket in [&mut socket, &mut other_socket].iter_mut() {
+        let edit = read_json(socket);
+        assert_eq!(edit["type"], "edit");
+        assert_eq!(edit["id"], id);
+        assert_eq!(edit["msg"], "Hello");
+    }
+
+    other_socket
+        .write_message(chat_request(
+            json!({ "type": "history", "room": "general" }),
+        ))
+        .unwrap();
+    let page = read_json(&mut other_socket);
+    assert_eq!(page["messages"][0]["msg"], "Hello");
+    assert!(page["messages"][0]["edited"].is_number());
+
+    // deletions are broadcast to the room and removed from the history
+    socket
+        .write_message(chat_request(json!({ "type": "delete", "id": id })))
+        .unwrap();
+    for socket in [&mut socket, &mut other_socket].iter_mut() {
+        assert_eq!(
+            socket.read_message().unwrap().to_string(),
+            format!(r#"{{"type":"delete","room":"general","id":{}}}"#, id)
+        );
+    }
+
+    socket
+        .write_message(chat_request(json!({ "type": "delete", "id": id })))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "not_found");
+
+    // messages can only be changed within the edit window
+    let old = StoredMessage {
+        id: 1,
+        room: "general".to_string(),
+        user: "Foo".to_string(),
+        time: Utc::now().timestamp() - 3600,
+        msg: "Long ago".to_string(),
+        edited: None,
+        reply_to: None,
+        reactions: Vec::new(),
+        badges: Vec::new(),
+        attachment: None,
+    };
+    store.insert(&old).unwrap();
+    socket
+        .write_message(chat_request(
+            json!({ "type": "edit", "id": 1, "text": "Not so long ago" }),
+        ))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "forbidden");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_reactions() {
+    let url = init_chat().await;
+    let jwt = SessionJwt::new("Foo".to_string()).as_jwt().unwrap();
+    let other_jwt = SessionJwt::new("Bar".to_string()).as_jwt().unwrap();
+
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+    let mut other_socket = connect_chat(&url, &other_jwt);
+    other_socket.read_message().unwrap();
+    socket.read_message().unwrap();
+
+    socket
+        .write_message(chat_request(json!({ "type": "send", "text": "Hello" })))
+        .unwrap();
+    let id = read_json(&mut socket)["id"].as_i64().unwrap();
+    other_socket.read_message().unwrap();
+
+    let react = |emoji: &str, add: bool| {
+        chat_request(json!({ "type": "react", "id": id, "emoji": emoji, "add": add }))
+    };
+
+    // every change is broadcast with the aggregated reactions
+    other_socket.write_message(react("👍", true)).unwrap();
+    for socket in [&mut socket, &mut other_socket].iter_mut() {
+        assert_eq!(
+            socket.read_message().unwrap().to_string(),
+            format!(
+                r#"{{"type":"reactions","room":"general","id":{},"reactions":[{{"emoji":"👍","count":1,"users":["Bar"]}}]}}"#,
+                id
+            )
+        );
+    }
+
+    socket.write_message(react("👍", true)).unwrap();
+    socket.read_message().unwrap();
+    assert_eq!(read_json(&mut other_socket)["reactions"][0]["count"], 2);
+
+    other_socket.write_message(react("👍", false)).unwrap();
+    socket.read_message().unwrap();
+    assert_eq!(
+        read_json(&mut other_socket)["reactions"][0]["users"],
+        json!(["Foo"])
+    );
+
+    // reactions can not contain text
+    socket.write_message(react("lol", true)).unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "invalid_request");
+
+    // or invisible control and formatting characters, joiners in emoji sequences are fine
+    socket.write_message(react("👍\u{202e}", true)).unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "invalid_request");
+    socket.write_message(react("\u{7}", true)).unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "invalid_request");
+    assert!(message_store::is_valid_emoji("👩\u{200d}🚀"));
+    assert!(!message_store::is_valid_emoji("👩\u{200d}"));
+
+    // the reactions are part of the history
+    let mut laptop_socket = connect_chat(&url, &other_jwt);
+    laptop_socket.read_message().unwrap();
+    let page = read_json(&mut laptop_socket);
+    assert_eq!(
+        page["messages"][0]["reactions"],
+        json!([{ "emoji": "👍", "count": 1, "users": ["Foo"] }])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_replies() {
+    let url = init_chat().await;
+    let jwt = SessionJwt::new("Foo".to_string()).as_jwt().unwrap();
+
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+
+    let long_text = "a".repeat(150);
+    socket
+        .write_message(chat_request(json!({ "type": "send", "text": long_text })))
+        .unwrap();
+    let id = read_json(&mut socket)["id"].as_i64().unwrap();
+
+    // replies quote the start of the message they refer to
+    socket
+        .write_message(chat_request(
+            json!({ "type": "send", "text": "Indeed", "reply_to": id }),
+        ))
+        .unwrap();
+    let reply = read_json(&mut socket);
+    assert_eq!(reply["reply_to"], id);
+    assert_eq!(
+        reply["quote"],
+        json!({ "id": id, "user": "Foo", "msg": format!("{}…", "a".repeat(100)) })
+    );
+
+    socket
+        .write_message(chat_request(
+            json!({ "type": "send", "text": "Right", "reply_to": reply["id"] }),
+        ))
+        .unwrap();
+    socket.read_message().unwrap();
+
+    // replies should refer to an existing message in the same room
+    socket
+        .write_message(chat_request(
+            json!({ "type": "send", "text": "What?", "reply_to": 1 }),
+        ))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "not_found");
+
+    socket
+        .write_message(chat_request(json!({ "type": "join", "room": "rust" })))
+        .unwrap();
+    socket.read_message().unwrap();
+    socket
+        .write_message(chat_request(
+            json!({ "type": "send", "room": "rust", "text": "Elsewhere", "reply_to": id }),
+        ))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "invalid_request");
+
+    // the whole thread can be retrieved
+    socket
+        .write_message(chat_request(json!({ "type": "thread", "id": id })))
+        .unwrap();
+    let thread = read_json(&mut socket);
+    assert_eq!(thread["type"], "thread");
+    assert_eq!(thread["room"], "general");
+    let texts: Vec<&str> = thread["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["msg"].as_str().unwrap())
+        .collect();
+    assert_eq!(texts, vec![long_text.as_str(), "Indeed", "Right"]);
+    assert_eq!(thread["messages"][2]["quote"]["msg"], "Indeed");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rate_limit() {
+    dotenv().ok();
+    let config = ChatConfig::from_env();
+    let state = ChatState {
+        config: ChatConfig {
+            rate_limits: RateLimits {
+                connection: BucketLimit {
+                    burst: 3,
+                    refill: 1.0,
+                },
+                strikes: 3,
+                mute_duration: Duration::from_secs(1),
+                ..config.rate_limits
+            },
+            ..config
+        },
+        ..ChatState::default()
+    };
+    let url = init_chat_with(state).await;
+    let jwt = SessionJwt::new("Foo".to_string()).as_jwt().unwrap();
+    let other_jwt = SessionJwt::new("Bar".to_string()).as_jwt().unwrap();
+
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+    let mut other_socket = connect_chat(&url, &other_jwt);
+    other_socket.read_message().unwrap();
+    socket.read_message().unwrap();
+
+    let send = |text: &str| chat_request(json!({ "type": "send", "text": text }));
+
+    // the burst is handled, the first overflow is warned and later ones are dropped
+    for i in 0..6 {
+        socket.write_message(send(&i.to_string())).unwrap();
+    }
+    for i in 0..3 {
+        assert_eq!(read_json(&mut socket)["msg"], i.to_string());
+        assert_eq!(read_json(&mut other_socket)["msg"], i.to_string());
+    }
+    let warning = read_json(&mut socket);
+    assert_eq!(warning["type"], "warning");
+    assert_eq!(warning["code"], "rate_limited");
+
+    // repeated overflows get the user muted
+    let muted = read_json(&mut socket);
+    assert_eq!(muted["type"], "error");
+    assert_eq!(muted["code"], "muted");
+
+    // other users are not affected
+    other_socket.write_message(send("Quiet please")).unwrap();
+    assert_eq!(read_json(&mut socket)["msg"], "Quiet please");
+    assert_eq!(read_json(&mut other_socket)["msg"], "Quiet please");
+
+    // after the mute the user can post again
+    thread::sleep(Duration::from_millis(1100));
+    socket.write_message(send("Sorry")).unwrap();
+    assert_eq!(read_json(&mut socket)["msg"], "Sorry");
+}
+
+#[tokio::test]
+async fn test_client_queue() {
+    let metrics = Arc::new(QueueMetrics::default());
+
+    // slow clients miss the oldest messages
+    let (tx, rx) = client_queue::channel(2, QueuePolicy::DropOldest, metrics.clone());
+    for text in ["one", "two", "three"].iter() {
+        assert!(tx.send(Message::text(*text)));
+    }
+    assert_eq!(tx.depth(), 2);
+    assert_eq!(rx.recv().await, Some(Message::text("two")));
+    assert_eq!(rx.recv().await, Some(Message::text("three")));
+
+    // or are disconnected
+    let (other_tx, other_rx) = client_queue::channel(2, QueuePolicy::Disconnect, metrics.clone());
+    assert!(other_tx.send(Message::text("one")));
+    assert!(other_tx.send(Message::text("two")));
+    assert!(!other_tx.send(Message::text("three")));
+    other_tx.overflowed().await;
+    assert_eq!(other_rx.recv().await, None);
+
+    let stats = metrics.stats(vec![&tx, &other_tx].into_iter());
+    assert_eq!(
+        stats,
+        QueueStats {
+            connections: 2,
+            queued: 0,
+            max_depth: 0,
+            dropped: 4,
+            disconnected: 1,
+        }
+    );
+
+    // messages for clients that are gone are refused
+    drop(rx);
+    assert!(!tx.send(Message::text("four")));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_queue_metrics() {
+    let url = init_chat().await;
+    let jwt = SessionJwt::new("Foo Bar".to_string()).as_jwt().unwrap();
+    let admin_jwt = SessionJwt::with_roles("Admin".to_string(), vec![Role::Admin])
+        .as_jwt()
+        .unwrap();
+
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+    let mut admin_socket = connect_chat(&url, &admin_jwt);
+    admin_socket.read_message().unwrap();
+    socket.read_message().unwrap();
+
+    // only admins can view the metrics
+    socket
+        .write_message(chat_request(json!({ "type": "metrics" })))
+        .unwrap();
+    assert_eq!(read_json(&mut socket)["code"], "forbidden");
+
+    admin_socket
+        .write_message(chat_request(json!({ "type": "metrics" })))
+        .unwrap();
+    let metrics = read_json(&mut admin_socket);
+    assert_eq!(metrics["type"], "metrics");
+    assert_eq!(metrics["connections"], 2);
+    assert_eq!(metrics["dropped"], 0);
+    assert_eq!(metrics["disconnected"], 0);
+}
+
+// fan out messages to a room with thousands of members, comparing a single shared encoding with
+// encoding every message per recipient.
+// Run with `cargo test --release bench_fanout -- --ignored --nocapture`
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn bench_fanout() {
+    const CLIENTS: usize = 5000;
+    const MESSAGES: usize = 200;
+
+    let message = StoredMessage {
+        id: 1,
+        room: "general".to_string(),
+        user: "Foo".to_string(),
+        time: Utc::now().timestamp(),
+        msg: "Hello World! ".repeat(10),
+        edited: None,
+        reply_to: None,
+        reactions: Vec::new(),
+        badges: Vec::new(),
+        attachment: None,
+    };
+    let response = ChatResponse::Message(ChatMessage::from_stored(&message, false, None));
+
+    for shared in [true, false].iter() {
+        let metrics = Arc::new(QueueMetrics::default());
+        let directory = RoomDirectory::default();
+        let mut consumers = Vec::new();
+
+        for i in 0..CLIENTS {
+            let (tx, rx) =
+                client_queue::channel(MESSAGES, QueuePolicy::Disconnect, metrics.clone());
+            let member = Member {
+                addr: SocketAddr::from(([127, 0, 0, 1], i as u16 + 1)),
+                user: format!("User {}", i),
+                tx,
+            };
+            directory.join("general", member);
+            consumers.push(tokio::spawn(async move {
+                for _ in 0..MESSAGES {
+                    rx.recv().await.unwrap();
+                }
+            }));
+        }
+
+        let started = Instant::now();
+        for _ in 0..MESSAGES {
+            if *shared {
+                directory.send("general", &response.to_shared(), |_| true);
+            } else {
+                for member in directory.members("general").iter() {
+                    member.tx.send(response.to_message());
+                }
+            }
+        }
+        for consumer in consumers {
+            consumer.await.unwrap();
+        }
+
+        let elapsed = started.elapsed();
+        println!(
+            "{} {} messages to {} clients in {:?}: {:.0} deliveries/s",
+            if *shared { "shared:" } else { "per recipient:" },
+            MESSAGES,
+            CLIENTS,
+            elapsed,
+            (MESSAGES * CLIENTS) as f64 / elapsed.as_secs_f64()
+        );
+        assert_eq!(metrics.stats(std::iter::empty()).dropped, 0);
+    }
+}
+
+#[test]
+fn test_store_migrations() {
+    let path = env::temp_dir().join(format!("irma-chat-{}.db", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    // a database created before its schema was versioned, with some of the later columns
+    {
+        let connection = rusqlite::Connection::open(path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE messages (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    room TEXT NOT NULL,
+                    user TEXT NOT NULL,
+                    time INTEGER NOT NULL,
+                    msg TEXT NOT NULL,
+                    edited INTEGER
+                );
+                INSERT INTO messages (id, room, user, time, msg, edited)
+                VALUES (1, 'general', 'Foo', 1612137600, 'Hello old world', 1612137660);",
+            )
+            .unwrap();
+    }
+
+    // the missing columns and tables are added, existing messages are kept and indexed
+    for _ in 0..2 {
+        let store = SqliteStore::open(path).unwrap();
+        let message = store.get(1).unwrap().unwrap();
+        assert_eq!(message.msg, "Hello old world");
+        assert_eq!(message.edited, Some(1612137660));
+        assert_eq!(message.reply_to, None);
+        assert!(message.badges.is_empty());
+        assert_eq!(message.attachment, None);
+
+        let query = SearchQuery {
+            query: Some("old".to_string()),
+            ..SearchQuery::default()
+        };
+        assert_eq!(store.search("general", &query, None, 10).unwrap().len(), 1);
+    }
+
+    let connection = rusqlite::Connection::open(path).unwrap();
+    let version: i64 = connection
+        .query_row("PRAGMA user_version", rusqlite::params![], |row| row.get(0))
+        .unwrap();
+    assert_eq!(version as usize, message_store::SCHEMA_VERSION);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_message_ids_after_restart() {
+    dotenv().ok();
+    // a history with ids ahead of the clock, i.e. after the clock of the server was turned back
+    let store = MemoryStore::default();
+    let last = (Utc::now().timestamp_millis() + 60_000) << 10;
+    store
+        .insert(&StoredMessage {
+            id: last,
+            room: "general".to_string(),
+            user: "Foo".to_string(),
+            time: 0,
+            msg: "Hello".to_string(),
+            edited: None,
+            reply_to: None,
+            reactions: Vec::new(),
+            badges: Vec::new(),
+            attachment: None,
+        })
+        .unwrap();
+
+    let state = ChatState::new(
+        Arc::new(store),
+        Arc::new(MemoryAttachments::default()),
+        ChatConfig::from_env(),
+    );
+    assert_eq!(state.ids.next(), last + 1);
+}
+
+#[test]
+fn test_message_store() {
+    let stores: Vec<Box<dyn MessageStore>> = vec![
+        Box::new(MemoryStore::default()),
+        Box::new(SqliteStore::open(":memory:").unwrap()),
+    ];
+
+    let badge = Badge {
+        credential: "pbdf.pbdf.idin".to_string(),
+        issuer: "pbdf.pbdf".to_string(),
+        attributes: vec!["familyname".to_string(), "initials".to_string()],
+        verified: 1,
+    };
+    let message = |id: i64, room: &str, msg: &str| StoredMessage {
+        id,
+        room: room.to_string(),
+        user: "Foo".to_string(),
+        time: id,
+        msg: msg.to_string(),
+        edited: None,
+        reply_to: None,
+        reactions: Vec::new(),
+        badges: vec![badge.clone()],
+        attachment: None,
+    };
+
+    for store in stores {
+        assert_eq!(store.last_id().unwrap(), None);
+        for i in 1..=5 {
+            store
+                .insert(&message(i, "general", &i.to_string()))
+                .unwrap();
+            store
+                .insert(&message(i + 10, "rust", "Hello Rust!"))
+                .unwrap();
+        }
+        assert_eq!(store.last_id().unwrap(), Some(15));
+
+        let page = store.history("general", None, 2).unwrap();
+        let texts: Vec<&str> = page.iter().map(|message| message.msg.as_str()).collect();
+        assert_eq!(texts, vec!["4", "5"]);
+
+        let page = store.history("general", Some(page[0].id), 10).unwrap();
+        let texts: Vec<&str> = page.iter().map(|message| message.msg.as_str()).collect();
+        assert_eq!(texts, vec!["1", "2", "3"]);
+        assert_eq!(page[0].badges, vec![badge.clone()]);
+
+        assert!(store.history("other", None, 10).unwrap().is_empty());
+
+        // edits and deletions are reflected in the history
+        store.edit(2, "two", 42).unwrap();
+        store.delete(3).unwrap();
+        assert_eq!(store.get(2).unwrap().unwrap().edited, Some(42));
+        assert_eq!(store.get(3).unwrap(), None);
+
+        let page = store.history("general", None, 10).unwrap();
+        let texts: Vec<&str> = page.iter().map(|message| message.msg.as_str()).collect();
+        assert_eq!(texts, vec!["1", "two", "4", "5"]);
+
+        // reactions are aggregated per emoji
+        store.react(4, "👍", "Foo", true).unwrap();
+        store.react(4, "🎉", "Foo", true).unwrap();
+        store.react(4, "👍", "Bar", true).unwrap();
+        store.react(4, "👍", "Bar", true).unwrap();
+        let reacted = store.react(4, "🎉", "Foo", false).unwrap().unwrap();
+        assert_eq!(
+            reacted.reactions,
+            vec![Reaction {
+                emoji: "👍".to_string(),
+                count: 2,
+                users: vec!["Foo".to_string(), "Bar".to_string()],
+            }]
+        );
+        assert_eq!(store.history("general", None, 2).unwrap()[0], reacted);
+        assert_eq!(store.react(3, "👍", "Foo", true).unwrap(), None);
+
+        // threads contain the nested replies to a message
+        for (id, reply_to) in [(20, 4), (21, 1), (22, 20)].iter() {
+            let reply = StoredMessage {
+                reply_to: Some(*reply_to),
+                ..message(*id, "general", "Re")
+            };
+            store.insert(&reply).unwrap();
+        }
+        let thread = store.thread(4).unwrap();
+        let ids: Vec<i64> = thread.iter().map(|message| message.id).collect();
+        assert_eq!(ids, vec![4, 20, 22]);
+        assert_eq!(thread[0].reactions.len(), 1);
+        assert!(store.thread(3).unwrap().is_empty());
+
+        // attachments are stored with their message
+        let attachment = Attachment {
+            name: "cat.png".to_string(),
+            mime: "image/png".to_string(),
+            size: 5,
+            hash: attachments::hash(b"meow!"),
+        };
+        let with_attachment = StoredMessage {
+            attachment: Some(attachment.clone()),
+            ..message(30, "general", "")
+        };
+        store.insert(&with_attachment).unwrap();
+        assert_eq!(store.get(30).unwrap().unwrap().attachment, Some(attachment));
+
+        // messages can be searched by keywords, matching the start of words
+        let search = |room: &str, query: SearchQuery, before: Option<i64>| -> Vec<i64> {
+            let results = store.search(room, &query, before, 10).unwrap();
+            results.iter().map(|message| message.id).collect()
+        };
+        let keywords = |query: &str| SearchQuery {
+            query: Some(query.to_string()),
+            ..SearchQuery::default()
+        };
+        assert_eq!(
+            search("rust", keywords("hel RUST"), None),
+            vec![11, 12, 13, 14, 15]
+        );
+        assert_eq!(search("rust", keywords("hello"), Some(13)), vec![11, 12]);
+        assert!(search("general", keywords("hello"), None).is_empty());
+
+        // the index follows edits and deletions
+        assert_eq!(search("general", keywords("two"), None), vec![2]);
+        assert!(search("general", keywords("2"), None).is_empty());
+        assert!(search("general", keywords("3"), None).is_empty());
+
+        // and can be combined with the author and a time range
+        let query = SearchQuery {
+            user: Some("Foo".to_string()),
+            from: Some(4),
+            until: Some(5),
+            ..SearchQuery::default()
+        };
+        assert_eq!(search("general", query, None), vec![4, 5]);
+        let query = SearchQuery {
+            user: Some("Bar".to_string()),
+            ..keywords("re")
+        };
+        assert!(search("general", query, None).is_empty());
+
+        // mentions are kept until they are read, or the message is deleted
+        assert_eq!(store.authors().unwrap(), vec!["Foo".to_string()]);
+        store.add_mention("Bar", 4).unwrap();
+        store.add_mention("Bar", 4).unwrap();
+        store.add_mention("Bar", 5).unwrap();
+        let unread: Vec<i64> = store
+            .unread_mentions("Bar")
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(unread, vec![4, 5]);
+        store.read_mention("Bar", 4).unwrap();
+        store.delete(5).unwrap();
+        assert!(store.unread_mentions("Bar").unwrap().is_empty());
+    }
+}
+
+#[test]
+fn test_auth_message_actions() {
+    let request = |text: &str| SocketRequest::from(Message::Text(text.to_string()));
+
+    // only the kind of a message is logged, never the tokens or attribute values it contains
+    assert_eq!(request("start").action(), "start");
+    assert_eq!(
+        request(r#"{"action": "disclose", "token": "secret", "attributes": []}"#).action(),
+        "disclose"
+    );
+    assert_eq!(request("secret").action(), "unknown");
+    assert_eq!(SocketResponse::jwt("secret".to_string()).action(), "jwt");
+}
+
+#[test]
+fn test_resolve_mentions() {
+    let subjects: Vec<String> = ["Foo", "Foo Bar", "owl#aaaaaaaaaaaa", "owl#bbbbbbbbbbbb"]
+        .iter()
+        .map(|subject| subject.to_string())
+        .collect();
+
+    // the longest name is mentioned, names should end at a word boundary
+    let resolved = mentions::resolve("Hi @Foo Bar, @foo and @Foobar", &subjects);
+    assert_eq!(resolved.subjects, vec!["Foo Bar", "Foo"]);
+    assert!(resolved.ambiguous.is_empty());
+
+    // pseudonyms can only be mentioned by their display name when it is unique
+    let resolved = mentions::resolve("@owl @owl#bbbbbbbbbbbb", &subjects);
+    assert_eq!(resolved.subjects, vec!["owl#bbbbbbbbbbbb"]);
+    assert_eq!(
+        resolved.ambiguous,
+        vec![(
+            "owl".to_string(),
+            vec![
+                "owl#aaaaaaaaaaaa".to_string(),
+                "owl#bbbbbbbbbbbb".to_string()
+            ]
+        )]
+    );
+
+    // only the first mentions of a message are resolved
+    let text = format!("{}@Foo Bar", "@nobody ".repeat(mentions::MAX_MENTIONS));
+    assert!(mentions::resolve(&text, &subjects).subjects.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_history() {
+    dotenv().ok();
+    let state = ChatState {
+        config: ChatConfig {
+            history_size: 2,
+            ..ChatConfig::from_env()
+        },
+        ..ChatState::default()
+    };
+    let url = init_chat_with(state).await;
+    let jwt = SessionJwt::new("Foo".to_string()).as_jwt().unwrap();
+    let other_jwt = SessionJwt::new("Bar".to_string()).as_jwt().unwrap();
+
+    // nothing to replay in a new room
+    let mut socket = connect_chat(&url, &jwt);
+    socket.read_message().unwrap();
+    let mut ids = Vec::new();
+    for text in ["one", "two", "three"].iter() {
+        socket
+            .write_message(chat_request(json!({ "type": "send", "text": text })))
+            .unwrap();
+        ids.push(read_json(&mut socket)["id"].as_i64().unwrap());
+    }
+
+    // message ids are ordered by the time the messages were sent
+    assert!(ids[0] < ids[1] && ids[1] < ids[2]);
+
+    // the last messages are replayed after connecting
+    let mut other_socket = connect_chat(&url, &other_jwt);
+    other_socket.read_message().unwrap();
+    let time = Utc::now().timestamp();
+    assert_eq!(
+        other_socket.read_message().unwrap().to_string(),
+        format!(
+            "{{\"type\":\"history\",\"room\":\"general\",\"messages\":[\
+            {{\"id\":{},\"room\":\"general\",\"user\":\"Foo\",\"time\":{},\"its_me\":false,\"msg\":\"two\"}},\
+            {{\"id\":{},\"room\":\"general\",\"user\":\"Foo\",\"time\":{},\"its_me\":false,\"msg\":\"three\"}}\
+            ],\"cursor\":{}}}",
+            ids[1], time, ids[2], time, ids[1]
+        )
+    );
+
+    // older messages can be retrieved using the cursor
+    other_socket
+        .write_message(chat_request(
+            json!({ "type": "history", "room": "general", "before": ids[1] }),
+        ))
+        .unwrap();
+    let page = read_json(&mut other_socket);
+    assert_eq!(page["messages"][0]["msg"], "one");
+    assert_eq!(page["cursor"], Value::Null);
+
+    other_socket
+        .write_message(chat_request(json!({ "type": "history", "room": "rust" })))
+        .unwrap();
+    assert_eq!(read_json(&mut other_socket)["code"], "not_a_member");
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// what to relay after a typing update of a user
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TypingUpdate {
+    // the user started typing, this should be relayed and watched for a timeout
+    Started,
+    // the user stopped typing, this should be relayed
+    Stopped,
+    // nothing changed, or the update was throttled
+    Ignored,
+}
+
+// result of checking whether a typing user timed out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TypingExpiry {
+    // the user did not refresh its indicator in time, a stop should be relayed
+    Expired,
+    // the user is still typing, check again at the given time
+    Pending(Instant),
+    // the user is not typing anymore
+    Done,
+}
+
+#[derive(Debug)]
+struct Typing {
+    typing: bool,
+    relayed_at: Instant,
+    updated_at: Instant,
+}
+
+// administration of the users that are typing, per room
+#[derive(Debug, Default)]
+pub struct TypingState {
+    users: HashMap<(String, String), Typing>,
+}
+
+impl TypingState {
+    // register a typing update of a user in a room, throttling bursts of updates
+    pub fn update(
+        &mut self,
+        room: &str,
+        user: &str,
+        typing: bool,
+        throttle: Duration,
+    ) -> TypingUpdate {
+        let now = Instant::now();
+        self.users
+            .retain(|_, entry| entry.typing || now.duration_since(entry.relayed_at) < throttle);
+
+        let key = (room.to_string(), user.to_string());
+        let entry = match self.users.get_mut(&key) {
+            Some(entry) => entry,
+            None if typing => {
+                self.users.insert(
+                    key,
+                    Typing {
+                        typing,
+                        relayed_at: now,
+                        updated_at: now,
+                    },
+                );
+                return TypingUpdate::Started;
+            }
+            None => return TypingUpdate::Ignored,
+        };
+
+        entry.updated_at = now;
+
+        match (entry.typing, typing) {
+            (true, false) => {
+                entry.typing = false;
+                entry.relayed_at = now;
+                TypingUpdate::Stopped
+            }
+            (false, true) if now.duration_since(entry.relayed_at) >= throttle => {
+                entry.typing = true;
+                entry.relayed_at = now;
+                TypingUpdate::Started
+            }
+            _ => TypingUpdate::Ignored,
+        }
+    }
+
+    // stop the typing indicator of a user that did not send an update for too long
+    pub fn expire(&mut self, room: &str, user: &str, timeout: Duration) -> TypingExpiry {
+        let now = Instant::now();
+        let key = (room.to_string(), user.to_string());
+
+        match self.users.get_mut(&key) {
+            Some(entry) if entry.typing => {
+                let deadline = entry.updated_at + timeout;
+
+                if deadline > now {
+                    return TypingExpiry::Pending(deadline);
+                }
+
+                entry.typing = false;
+                entry.relayed_at = now;
+                TypingExpiry::Expired
+            }
+            _ => TypingExpiry::Done,
+        }
+    }
+}